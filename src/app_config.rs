@@ -1,44 +1,714 @@
+use crate::utils::access_control::Role;
+use crate::utils::bot_error::{BotError, Language};
 use crate::utils::custom_result::ResultGram;
-use log::error;
+use crate::utils::external_tool::is_installed;
+use crate::utils::quota::{Limits, UserLimits};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+use url::Url;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+/// How often the config file is checked for changes
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static::lazy_static! {
+    /// Config loaded at startup or by the last successful reload
+    static ref CURRENT: RwLock<Option<AppConfig>> = RwLock::new(None);
+}
 
 #[derive(Clone)]
 pub struct AppConfig {
     pub api_id: i32,
     pub api_hash: String,
     pub bot_token: String,
+    pub session_file: String,
+    /// Handle messages sent while the bot was offline at startup
+    pub catch_up: bool,
+    /// Recently handled message ids, so caught up messages aren't handled twice
+    pub handled_file: String,
     pub download_directory: Vec<String>,
     pub user_id: i64,
+    pub allowed_users: Vec<String>,
+    /// Replies to users whose Telegram language isn't translated
+    pub language: Language,
+    pub users_file: String,
+    /// Per user quotas and directories
+    pub limits: Limits,
+    pub usage_file: String,
+    pub url_allowlist: Vec<String>,
+    /// Sites whose links are handed to yt-dlp, empty uses `ytdlp::DEFAULT_VIDEO_DOMAINS`
+    pub video_domains: Vec<String>,
+    pub external_tool_timeout: u64,
+    pub aria2_rpc_url: Option<String>,
+    pub aria2_rpc_secret: Option<String>,
+    pub on_download_hook: Option<String>,
+    pub system_command_cooldown: u64,
+    pub log_level: LevelFilter,
+    pub audit_file: String,
+    pub audit_max_size: u64,
+    pub audit_keep: usize,
+    pub history_file: String,
+    pub progress_log: bool,
+    pub progress_file: Option<String>,
+    pub control_socket: String,
+    pub metrics_address: Option<String>,
+    /// Seconds running downloads get to finish on SIGINT or SIGTERM
+    pub shutdown_grace_period: u64,
+    /// Downloads interrupted by shutdown, resumed at startup
+    pub pending_file: String,
+    /// Seconds offline before the admin is told once the bot is back
+    pub offline_alert_after: u64,
+    /// None if the config comes only from env
+    pub config_file: Option<String>,
+}
+
+/// Layout of `config.toml`, every value is optional so env can fill the gaps
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    telegram: TelegramSection,
+    users: UsersSection,
+    destinations: DestinationsSection,
+    rules: RulesSection,
+    limits: LimitsSection,
+    aria2: Aria2Section,
+    hooks: HooksSection,
+    logging: LoggingSection,
+    control: ControlSection,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TelegramSection {
+    api_id: Option<i32>,
+    api_hash: Option<String>,
+    bot_token: Option<String>,
+    session_file: Option<String>,
+    catch_up: Option<bool>,
+    handled_file: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct UsersSection {
+    admin: Option<i64>,
+    allowed: Option<Vec<String>>,
+    file: Option<String>,
+    language: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DestinationsSection {
+    directories: Option<Vec<String>>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RulesSection {
+    url_allowlist: Option<Vec<String>>,
+    video_domains: Option<Vec<String>>,
+    external_tool_timeout: Option<u64>,
+    system_command_cooldown: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    usage_file: Option<String>,
+    default: UserLimits,
+    /// Keys of toml tables are strings, they are parsed to user ids on load
+    users: HashMap<String, UserLimits>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Aria2Section {
+    rpc_url: Option<String>,
+    rpc_secret: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HooksSection {
+    on_download: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LoggingSection {
+    level: Option<String>,
+    audit_file: Option<String>,
+    audit_max_size: Option<u64>,
+    audit_keep: Option<usize>,
+    history_file: Option<String>,
+    progress: Option<bool>,
+    progress_file: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ControlSection {
+    socket: Option<String>,
+    metrics_address: Option<String>,
+    shutdown_grace_period: Option<u64>,
+    pending_file: Option<String>,
+    offline_alert_after: Option<u64>,
 }
 
 impl AppConfig {
-    pub fn from_env() -> ResultGram<Self> {
-        Ok(AppConfig {
-            api_id: parse_env("TELEGRAM_API_ID").ok_or("TELEGRAM_API_ID not found")?,
-            api_hash: parse_env("TELEGRAM_API_HASH").ok_or("TELEGRAM_API_HASH not found")?,
-            bot_token: parse_env("BOT_TOKEN").ok_or("BOT_TOKEN not found")?,
-            user_id: parse_env("USER_ID").ok_or("USER_ID not found")?,
-            download_directory: parse_env::<String>("DOWNLOAD_DIRECTORY")
-                .ok_or("DOWNLOAD_DIRECTORY not found")?
-                .split(',')
-                .map(|s| s.trim().to_string())
-                .collect(),
+    /// Load and validate the config, then make it current
+    /// `config_file` is the `--config` argument, it wins over `CONFIG_FILE`
+    pub fn init(config_file: Option<String>) -> ResultGram<Self> {
+        let config = Self::load(config_file)?;
+        log::set_max_level(config.log_level);
+        *CURRENT.write().unwrap() = Some(config.clone());
+        Ok(config)
+    }
+
+    /// Config loaded by `init` or the last reload
+    pub fn current() -> Self {
+        CURRENT
+            .read()
+            .unwrap()
+            .clone()
+            .expect("AppConfig::init must run first")
+    }
+
+    /// Load the config again, the current one is kept if the new one is invalid
+    pub fn reload() -> ResultGram<()> {
+        let current = Self::current();
+        let config = Self::load(current.config_file.clone())?;
+        if config.api_id != current.api_id
+            || config.api_hash != current.api_hash
+            || config.bot_token != current.bot_token
+        {
+            log::warn!("Telegram settings changed, they are used after a restart");
+        }
+        log::set_max_level(config.log_level);
+        *CURRENT.write().unwrap() = Some(config);
+        log::info!("Config reloaded");
+        Ok(())
+    }
+
+    /// Read `CONFIG_FILE` (default `config.toml`) and let env variables override it
+    /// Reports every invalid or missing value at once
+    fn load(config_file: Option<String>) -> ResultGram<Self> {
+        let mut errors: Vec<String> = Vec::new();
+        let (file, config_file) = match config_file.or_else(|| parse_env_optional("CONFIG_FILE")) {
+            Some(path) => (read_config_file(&path, &mut errors), Some(path)),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => (
+                read_config_file(DEFAULT_CONFIG_FILE, &mut errors),
+                Some(DEFAULT_CONFIG_FILE.to_string()),
+            ),
+            None => (ConfigFile::default(), None),
+        };
+        let errors = &mut errors;
+
+        let config = AppConfig {
+            api_id: required("TELEGRAM_API_ID", "telegram.api_id", file.telegram.api_id, errors)
+                .unwrap_or_default(),
+            api_hash: required(
+                "TELEGRAM_API_HASH",
+                "telegram.api_hash",
+                file.telegram.api_hash,
+                errors,
+            )
+            .unwrap_or_default(),
+            bot_token: required("BOT_TOKEN", "telegram.bot_token", file.telegram.bot_token, errors)
+                .unwrap_or_default(),
+            session_file: value("SESSION_FILE", file.telegram.session_file, errors)
+                .unwrap_or_else(|| "bot.session".to_string()),
+            catch_up: value("CATCH_UP", file.telegram.catch_up, errors).unwrap_or(false),
+            handled_file: value("HANDLED_FILE", file.telegram.handled_file, errors)
+                .unwrap_or_else(|| "handled.json".to_string()),
+            user_id: required("USER_ID", "users.admin", file.users.admin, errors)
+                .unwrap_or_default(),
+            download_directory: list("DOWNLOAD_DIRECTORY", file.destinations.directories),
+            allowed_users: list("ALLOWED_USERS", file.users.allowed),
+            language: value("LANGUAGE", file.users.language, errors)
+                .map(|code: String| match Language::from_code(&code) {
+                    Some(language) => language,
+                    None => {
+                        errors.push(format!("users.language: unknown language {code}, use en, de or es"));
+                        Language::English
+                    }
+                })
+                .unwrap_or(Language::English),
+            users_file: value("USERS_FILE", file.users.file, errors)
+                .unwrap_or_else(|| "users.json".to_string()),
+            limits: Limits {
+                users: user_limits(file.limits.users, errors),
+                default: file.limits.default,
+            },
+            usage_file: value("USAGE_FILE", file.limits.usage_file, errors)
+                .unwrap_or_else(|| "usage.json".to_string()),
+            url_allowlist: list("URL_ALLOWLIST", file.rules.url_allowlist),
+            video_domains: list("VIDEO_DOMAINS", file.rules.video_domains),
+            external_tool_timeout: value(
+                "EXTERNAL_TOOL_TIMEOUT",
+                file.rules.external_tool_timeout,
+                errors,
+            )
+            .unwrap_or(3 * 60 * 60),
+            aria2_rpc_url: value("ARIA2_RPC_URL", file.aria2.rpc_url, errors),
+            aria2_rpc_secret: value("ARIA2_RPC_SECRET", file.aria2.rpc_secret, errors),
+            on_download_hook: value("ON_DOWNLOAD_HOOK", file.hooks.on_download, errors),
+            system_command_cooldown: value(
+                "SYSTEM_COMMAND_COOLDOWN",
+                file.rules.system_command_cooldown,
+                errors,
+            )
+            .unwrap_or(0),
+            log_level: value("LOG_LEVEL", file.logging.level, errors)
+                .map(|level: String| match level.parse() {
+                    Ok(level) => level,
+                    Err(_) => {
+                        errors.push(format!(
+                            "logging.level: unknown level {level}, use error, warn, info, debug or trace"
+                        ));
+                        LevelFilter::Info
+                    }
+                })
+                .unwrap_or(LevelFilter::Info),
+            audit_file: value("AUDIT_FILE", file.logging.audit_file, errors)
+                .unwrap_or_else(|| "audit.log".to_string()),
+            audit_max_size: value("AUDIT_MAX_SIZE", file.logging.audit_max_size, errors)
+                .unwrap_or(10 * 1024 * 1024),
+            audit_keep: value("AUDIT_KEEP", file.logging.audit_keep, errors).unwrap_or(5),
+            history_file: value("HISTORY_FILE", file.logging.history_file, errors)
+                .unwrap_or_else(|| "history.json".to_string()),
+            progress_log: value("LOG_PROGRESS", file.logging.progress, errors).unwrap_or(false),
+            progress_file: value("PROGRESS_FILE", file.logging.progress_file, errors),
+            control_socket: value("CONTROL_SOCKET", file.control.socket, errors)
+                .unwrap_or_else(|| "telegram_bot.sock".to_string()),
+            metrics_address: value("METRICS_ADDRESS", file.control.metrics_address, errors),
+            shutdown_grace_period: value(
+                "SHUTDOWN_GRACE_PERIOD",
+                file.control.shutdown_grace_period,
+                errors,
+            )
+            .unwrap_or(30),
+            pending_file: value("PENDING_FILE", file.control.pending_file, errors)
+                .unwrap_or_else(|| "pending.json".to_string()),
+            offline_alert_after: value(
+                "OFFLINE_ALERT_AFTER",
+                file.control.offline_alert_after,
+                errors,
+            )
+            .unwrap_or(5 * 60),
+            config_file,
+        };
+        config.validate(errors);
+
+        if !errors.is_empty() {
+            return Err(BotError::Config(errors.join("\n")).into());
+        }
+        Ok(config)
+    }
+
+    /// Checks that need more than parsing a single value
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.api_hash.is_empty() {
+            errors.push("telegram.api_hash: can't be empty".to_string());
+        }
+        if self.bot_token.is_empty() {
+            errors.push("telegram.bot_token: can't be empty".to_string());
+        }
+        if self.download_directory.is_empty() {
+            errors.push(
+                "destinations.directories: missing, set it in the config file or DOWNLOAD_DIRECTORY"
+                    .to_string(),
+            );
+        }
+        // Entries look like `123456` or `123456:read-only`
+        for entry in &self.allowed_users {
+            let valid = match entry.split_once(':') {
+                Some((id, role)) => id.trim().parse::<i64>().is_ok() && Role::parse(role).is_some(),
+                None => entry.trim().parse::<i64>().is_ok(),
+            };
+            if !valid {
+                errors.push(format!(
+                    "users.allowed: invalid entry {entry}, use id or id:role"
+                ));
+            }
+        }
+        let user_limits = self
+            .limits
+            .users
+            .iter()
+            .map(|(user_id, limits)| (format!("limits.users.{user_id}"), limits));
+        for (key, limits) in
+            std::iter::once(("limits.default".to_string(), &self.limits.default)).chain(user_limits)
+        {
+            for directory in &limits.directories {
+                if !self.download_directory.contains(directory) {
+                    errors.push(format!(
+                        "{key}.directories: {directory} is not in destinations.directories"
+                    ));
+                }
+            }
+        }
+        if let Some(url) = &self.aria2_rpc_url {
+            if let Err(e) = Url::parse(url) {
+                errors.push(format!("aria2.rpc_url: {e} ({url})"));
+            }
+        }
+        if let Some(hook) = &self.on_download_hook {
+            if !Path::new(hook).is_file() && !is_installed(hook) {
+                errors.push(format!("hooks.on_download: {hook} not found"));
+            }
+        }
+        if let Some(address) = &self.metrics_address {
+            if let Err(e) = address.parse::<SocketAddr>() {
+                errors.push(format!("control.metrics_address: {e} ({address})"));
+            }
+        }
+    }
+}
+
+/// Reload on SIGHUP and when the config file changes
+pub fn watch_config() {
+    #[cfg(unix)]
+    tokio::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                log::error!("Can't listen for SIGHUP: {e}");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            log::info!("Got SIGHUP, reloading config");
+            reload_config();
+        }
+    });
+
+    tokio::spawn(async {
+        let mut modified = config_modified();
+        loop {
+            tokio::time::sleep(CONFIG_POLL_INTERVAL).await;
+            let now = config_modified();
+            if now != modified {
+                modified = now;
+                log::info!("Config file changed, reloading");
+                reload_config();
+            }
+        }
+    });
+}
+
+fn reload_config() {
+    if let Err(e) = AppConfig::reload() {
+        log::error!("{e}\nKeeping the previous config");
+    }
+}
+
+fn config_modified() -> Option<SystemTime> {
+    let path = AppConfig::current().config_file?;
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn read_config_file(path: &str, errors: &mut Vec<String>) -> ConfigFile {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            errors.push(format!("{path}: {e}"));
+            return ConfigFile::default();
+        }
+    };
+    // Errors of toml name the key and the line
+    toml::from_str(&content).unwrap_or_else(|e| {
+        errors.push(format!("{path}: {e}"));
+        ConfigFile::default()
+    })
+}
+
+/// Limits by user id, only set in the file
+fn user_limits(
+    from_file: HashMap<String, UserLimits>,
+    errors: &mut Vec<String>,
+) -> HashMap<i64, UserLimits> {
+    from_file
+        .into_iter()
+        .filter_map(|(user_id, limits)| match user_id.trim().parse::<i64>() {
+            Ok(id) => Some((id, limits)),
+            Err(_) => {
+                errors.push(format!("limits.users: invalid user id {user_id}"));
+                None
+            }
         })
+        .collect()
+}
+
+/// Env variable wins over the file, invalid env values are reported
+fn value<T>(variable: &str, from_file: Option<T>, errors: &mut Vec<String>) -> Option<T>
+where
+    T: FromStr,
+    <T as FromStr>::Err: Display,
+{
+    match parse_env_optional(variable) {
+        Some(raw) => match raw.parse::<T>() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                errors.push(format!("{variable}: {e} ({raw})"));
+                None
+            }
+        },
+        None => from_file,
     }
 }
 
-fn parse_env<T>(variable: &str) -> Option<T>
+/// Like `value`, but reports a missing value
+fn required<T>(
+    variable: &str,
+    key: &str,
+    from_file: Option<T>,
+    errors: &mut Vec<String>,
+) -> Option<T>
 where
     T: FromStr,
     <T as FromStr>::Err: Display,
 {
+    let missing = parse_env_optional(variable).is_none() && from_file.is_none();
+    if missing {
+        errors.push(format!(
+            "{key}: missing, set it in the config file or {variable}"
+        ));
+    }
+    value(variable, from_file, errors)
+}
+
+/// Optional variable, None if not set or empty
+fn parse_env_optional(variable: &str) -> Option<String> {
     dotenv::var(variable)
-        .map_err(|error| error!("{error}: {variable}"))
         .ok()
-        .and_then(|raw| {
-            raw.parse::<T>()
-                .map_err(|error| error!("{error}: {raw}"))
-                .ok()
-        })
+        .map(|raw| raw.trim().to_string())
+        .filter(|raw| !raw.is_empty())
+}
+
+/// Comma separated env variable wins over the file list
+fn list(variable: &str, from_file: Option<Vec<String>>) -> Vec<String> {
+    let items = match parse_env_optional(variable) {
+        Some(raw) => raw.split(',').map(|s| s.to_string()).collect(),
+        None => from_file.unwrap_or_default(),
+    };
+    items
+        .into_iter()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{name}-{}.toml", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn load_errors(path: &str) -> String {
+        match AppConfig::load(Some(path.to_string())) {
+            Ok(_) => panic!("{path} should not load"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    const VALID: &str = r#"
+        [telegram]
+        api_id = 12345
+        api_hash = "hash"
+        bot_token = "token"
+
+        [users]
+        admin = 1
+        allowed = ["2:downloader", "3"]
+
+        [destinations]
+        directories = ["./downloads", "./movies"]
+
+        [limits]
+        usage_file = "usage-test.json"
+
+        [limits.default]
+        daily_bytes = "5G"
+        max_concurrent = 2
+        directories = ["./downloads"]
+
+        [limits.users.2]
+        monthly_bytes = 1000
+        home_subfolder = true
+
+        [aria2]
+        rpc_url = "http://localhost:6800/jsonrpc"
+    "#;
+
+    #[test]
+    fn load_file() {
+        let path = write_config("config-valid", VALID);
+        let config = AppConfig::load(Some(path.clone())).unwrap();
+        assert_eq!(config.api_id, 12345);
+        assert_eq!(config.user_id, 1);
+        assert_eq!(config.allowed_users, ["2:downloader", "3"]);
+        assert_eq!(config.download_directory, ["./downloads", "./movies"]);
+        assert_eq!(config.usage_file, "usage-test.json");
+        assert_eq!(config.session_file, "bot.session");
+        assert_eq!(config.limits.default.daily_bytes, Some(5_000_000_000));
+        assert_eq!(config.limits.default.max_concurrent, Some(2));
+        assert_eq!(config.limits.users[&2].monthly_bytes, Some(1000));
+        assert!(config.limits.users[&2].home_subfolder);
+        assert_eq!(
+            config.aria2_rpc_url.as_deref(),
+            Some("http://localhost:6800/jsonrpc")
+        );
+        assert_eq!(config.config_file, Some(path.clone()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn every_invalid_value_is_reported() {
+        let path = write_config(
+            "config-invalid",
+            r#"
+            [telegram]
+            api_id = 12345
+            bot_token = ""
+
+            [users]
+            admin = 1
+            allowed = ["abc", "2:owner"]
+            language = "fr"
+
+            [destinations]
+            directories = ["./downloads"]
+
+            [limits.default]
+            directories = ["./elsewhere"]
+
+            [limits.users.someone]
+            daily_bytes = 1
+
+            [aria2]
+            rpc_url = "not a url"
+
+            [logging]
+            level = "loud"
+
+            [control]
+            metrics_address = "nowhere"
+            "#,
+        );
+        let errors = load_errors(&path);
+        for expected in [
+            "telegram.api_hash: missing",
+            "telegram.bot_token: can't be empty",
+            "users.allowed: invalid entry abc",
+            "users.allowed: invalid entry 2:owner",
+            "users.language: unknown language fr",
+            "limits.default.directories: ./elsewhere is not in destinations.directories",
+            "limits.users: invalid user id someone",
+            "aria2.rpc_url:",
+            "logging.level: unknown level loud",
+            "control.metrics_address:",
+        ] {
+            assert!(errors.contains(expected), "{expected} not in\n{errors}");
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let mut errors = Vec::new();
+        read_config_file(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/config.example.toml"),
+            &mut errors,
+        );
+        assert!(errors.is_empty(), "{errors:?}");
+    }
+
+    #[test]
+    fn file_errors_name_the_key() {
+        let path = write_config("config-unknown", "[rules]\nurl_alowlist = []\n");
+        assert!(load_errors(&path).contains("url_alowlist"));
+
+        let path_size = write_config(
+            "config-size",
+            "[limits.default]\ndaily_bytes = \"5 potatoes\"\n",
+        );
+        assert!(load_errors(&path_size).contains("invalid size 5 potatoes"));
+
+        let missing = format!("{path}.missing");
+        assert!(load_errors(&missing).contains(&missing));
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(path_size).unwrap();
+    }
+
+    #[test]
+    fn env_wins_over_file() {
+        let mut errors = Vec::new();
+        std::env::set_var("TEST_CONFIG_VALUE", " 42 ");
+        assert_eq!(
+            value::<u64>("TEST_CONFIG_VALUE", Some(7), &mut errors),
+            Some(42)
+        );
+        std::env::set_var("TEST_CONFIG_VALUE", "");
+        assert_eq!(
+            value::<u64>("TEST_CONFIG_VALUE", Some(7), &mut errors),
+            Some(7)
+        );
+        std::env::remove_var("TEST_CONFIG_VALUE");
+        assert_eq!(
+            value::<u64>("TEST_CONFIG_VALUE", Some(7), &mut errors),
+            Some(7)
+        );
+        assert!(errors.is_empty());
+
+        std::env::set_var("TEST_CONFIG_INVALID", "forty");
+        assert_eq!(
+            value::<u64>("TEST_CONFIG_INVALID", Some(7), &mut errors),
+            None
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(
+            errors[0].starts_with("TEST_CONFIG_INVALID:"),
+            "{}",
+            errors[0]
+        );
+        assert!(errors[0].ends_with("(forty)"), "{}", errors[0]);
+
+        std::env::remove_var("TEST_CONFIG_REQUIRED");
+        let mut errors = Vec::new();
+        assert_eq!(
+            required::<u64>("TEST_CONFIG_REQUIRED", "rules.key", None, &mut errors),
+            None
+        );
+        assert_eq!(
+            errors,
+            ["rules.key: missing, set it in the config file or TEST_CONFIG_REQUIRED"]
+        );
+    }
+
+    #[test]
+    fn lists_are_trimmed() {
+        let from_file = Some(vec![" a ".to_string(), "".to_string(), "b".to_string()]);
+        std::env::remove_var("TEST_CONFIG_LIST");
+        assert_eq!(list("TEST_CONFIG_LIST", from_file.clone()), ["a", "b"]);
+        std::env::set_var("TEST_CONFIG_LIST", "x, y,,z ");
+        assert_eq!(list("TEST_CONFIG_LIST", from_file), ["x", "y", "z"]);
+        std::env::remove_var("TEST_CONFIG_LIST");
+        assert!(list("TEST_CONFIG_LIST", None).is_empty());
+    }
 }
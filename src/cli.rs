@@ -0,0 +1,329 @@
+use clap::{Parser, Subcommand};
+use grammers_client::SignInError;
+use std::fs::create_dir_all;
+use std::future::Future;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::app_config::AppConfig;
+use crate::get_bot::{connect, get_bot};
+use crate::message_handler::url_handler::{file_name_from_url, write_response};
+use crate::utils::audit::{audit, AuditEntry};
+use crate::utils::control;
+use crate::utils::custom_result::ResultGram;
+use crate::utils::download_registry::{self, Download};
+use crate::utils::download_utils::{delete_file, download_media_to_file, path_in, plain_file_name};
+use crate::utils::gdrive::{parse_drive_link, DriveClient, DriveLink};
+use crate::utils::helper::get_document;
+use crate::utils::self_check::{check_admin, check_environment, has_failures, report, Check};
+use crate::utils::url_policy::UrlPolicy;
+
+/// How often a command line download prints its progress
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// Owner of downloads started from the command line
+const CLI_OWNER: &str = "command line";
+
+#[derive(Parser)]
+#[command(version, about = "Telegram bot to download files, links and videos")]
+pub struct Cli {
+    /// Config file, default is config.toml
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+    /// Session file, default is session_file from config
+    #[arg(long, global = true)]
+    pub session: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the bot, also used without a command
+    Run,
+    /// Sign in as bot or user and save the session
+    Login,
+    /// Check config and environment, then exit
+    Check,
+    /// Download a t.me message link or url without the chat
+    Download {
+        /// e.g. https://t.me/channel/123 or https://example.com/file.zip
+        link: String,
+        /// Directory to download to, default is the first download directory
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// See or cancel downloads of the running bot
+    Queue {
+        #[command(subcommand)]
+        action: QueueAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum QueueAction {
+    /// List running downloads
+    List,
+    /// Cancel a download by its id from list
+    Cancel { id: u64 },
+}
+
+/// Run the self-check, report every problem and exit
+pub async fn run_check(config: &AppConfig, session_file: &str) -> ResultGram<()> {
+    let mut checks = check_environment(config, session_file).await;
+    let bot = get_bot(config.clone(), session_file, false)
+        .await
+        .map_err(|e| e.to_string());
+    match bot {
+        Ok(bot) => {
+            checks.push(Check::ok("Connected to Telegram".to_string()));
+            checks.push(check_admin(&bot, config).await);
+        }
+        Err(e) => checks.push(Check::failed(format!("Can't connect to Telegram: {e}"))),
+    }
+    println!("{}", report(&checks));
+    if has_failures(&checks) {
+        return Err("Self-check failed".into());
+    }
+    Ok(())
+}
+
+/// Ask for a bot token or a phone number, a user session can download from chats the bot isn't in
+/// `session` is the `--session` argument, phone numbers need one besides the bot's
+pub async fn login(config: &AppConfig, session: Option<&str>) -> ResultGram<()> {
+    let session_file = session.unwrap_or(&config.session_file);
+    let client = connect(config, session_file, false).await?;
+    if client.is_authorized().await? {
+        println!("{session_file} is already signed in");
+        return Ok(());
+    }
+
+    let input = prompt("Phone number or bot token (empty to use the configured bot token): ")?;
+    if input.is_empty() || input.contains(':') {
+        let token = match input.is_empty() {
+            true => config.bot_token.clone(),
+            false => input,
+        };
+        client.bot_sign_in(&token).await?;
+    } else {
+        // `run` would act as the user if it were signed in to the bot's session
+        if session_file == config.session_file {
+            return Err(format!(
+                "{session_file} is the bot's session, sign in with a phone number with e.g. --session user.session login"
+            )
+            .into());
+        }
+        let login_token = client.request_login_code(&input).await?;
+        let code = prompt("Code you received in Telegram: ")?;
+        match client.sign_in(&login_token, &code).await {
+            Ok(_) => {}
+            Err(SignInError::PasswordRequired(password_token)) => {
+                let hint = password_token.hint().unwrap_or("none").to_string();
+                let password = prompt(&format!("Two-step verification password (hint: {hint}): "))?;
+                client.check_password(password_token, password).await?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    client.session().save_to_file(session_file)?;
+    println!("Signed in, session saved to {session_file}");
+    Ok(())
+}
+
+/// Download without the chat, `t.me` links need a session that can read the chat
+pub async fn download(
+    config: &AppConfig,
+    session_file: &str,
+    link: &str,
+    output: Option<String>,
+) -> ResultGram<()> {
+    let directory = output
+        .or_else(|| config.download_directory.first().cloned())
+        .ok_or("No download directory, use -o")?;
+    create_dir_all(&directory)?;
+    let url = Url::parse(link.trim())?;
+
+    let entry = AuditEntry::new(0, 0, "download")
+        .file_name(url.as_str())
+        .destination(&directory);
+    audit(config, entry.clone().result("started"));
+    let start_time = Instant::now();
+
+    let result = if matches!(url.host_str(), Some("t.me") | Some("telegram.me")) {
+        let (username, message_id) = parse_message_link(&url)
+            .ok_or("Only public message links like https://t.me/channel/123 are supported")?;
+        download_message(config, session_file, &username, message_id, &directory).await
+    } else if let Some(drive_link) = parse_drive_link(url.as_str()) {
+        download_drive(config, &drive_link, &directory).await
+    } else {
+        download_url(config, url, &directory).await
+    }
+    .map_err(|e| e.to_string());
+
+    let mut entry = entry.duration(start_time.elapsed()).outcome(&result, false);
+    if let Ok(files) = &result {
+        let size = files
+            .iter()
+            .filter_map(|file| file.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        entry = entry.size(size);
+    }
+    audit(config, entry);
+
+    for file in result? {
+        println!("Downloaded {}", file.display());
+    }
+    println!("Done in {} sec", start_time.elapsed().as_secs());
+    Ok(())
+}
+
+/// Ask the running bot through its control socket
+pub async fn queue(config: &AppConfig, action: QueueAction) -> ResultGram<()> {
+    let request = match action {
+        QueueAction::List => "list".to_string(),
+        QueueAction::Cancel { id } => format!("cancel {id}"),
+    };
+    print!(
+        "{}",
+        control::request(&config.control_socket, &request).await?
+    );
+    Ok(())
+}
+
+/// `https://t.me/<username>/<message id>`, private `t.me/c/` links need an access hash
+fn parse_message_link(url: &Url) -> Option<(String, i32)> {
+    let segments: Vec<&str> = url.path_segments()?.filter(|s| !s.is_empty()).collect();
+    match segments.as_slice() {
+        [username, message_id] if *username != "c" => {
+            Some((username.to_string(), message_id.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+async fn download_message(
+    config: &AppConfig,
+    session_file: &str,
+    username: &str,
+    message_id: i32,
+    directory: &str,
+) -> ResultGram<Vec<PathBuf>> {
+    let client = connect(config, session_file, false).await?;
+    if !client.is_authorized().await? {
+        return Err(format!("{session_file} is not signed in, run login first").into());
+    }
+    let chat = client
+        .resolve_username(username)
+        .await?
+        .ok_or(format!("{username} not found"))?;
+    let message = client
+        .get_messages_by_id(&chat, &[message_id])
+        .await?
+        .pop()
+        .flatten()
+        .ok_or("Message not found")?;
+    let document = get_document(message.clone()).ok_or("Message has no file")?;
+    // The sender picks the name, only the file name is kept like in the chat
+    let name =
+        plain_file_name(document.name()).unwrap_or_else(|| format!("{username}_{message_id}"));
+
+    let path = PathBuf::from(path_in(directory, &name).ok_or("Invalid file name")?);
+    let download = Download::new(0, CLI_OWNER, &name);
+    let result = print_progress(
+        download_media_to_file(client, message, &path, &download),
+        &download,
+    )
+    .await
+    .map_err(|e| e.to_string());
+    if result.is_err() {
+        delete_file(path.to_string_lossy().to_string()).await;
+    }
+    result?;
+    Ok(vec![path])
+}
+
+async fn download_drive(
+    config: &AppConfig,
+    link: &DriveLink,
+    directory: &str,
+) -> ResultGram<Vec<PathBuf>> {
+    let drive = DriveClient::new(UrlPolicy::from_config(config));
+    let directory = PathBuf::from(directory);
+    let name = match link {
+        DriveLink::File(id) | DriveLink::Folder(id) => id.clone(),
+    };
+    let download = Download::new(0, CLI_OWNER, &name);
+    let on_progress = |name: &str, downloaded: u64, total: u64| {
+        download.progress().set(name, downloaded, total);
+    };
+    let cancel_token = download.cancel_token();
+    let files = match link {
+        DriveLink::File(id) => print_progress(
+            drive.download_file(id, &directory, None, cancel_token, &on_progress),
+            &download,
+        )
+        .await
+        .map(|path| vec![path]),
+        DriveLink::Folder(id) => {
+            print_progress(
+                drive.download_folder(id, &directory, cancel_token, &on_progress),
+                &download,
+            )
+            .await
+        }
+    }?;
+    Ok(files)
+}
+
+async fn download_url(config: &AppConfig, url: Url, directory: &str) -> ResultGram<Vec<PathBuf>> {
+    let policy = UrlPolicy::from_config(config);
+    let name = file_name_from_url(&url).unwrap_or_else(|| "download".to_string());
+    let path_text = path_in(directory, &name).ok_or("Invalid file name")?;
+    let path = PathBuf::from(&path_text);
+
+    let response = policy.get(url).await?.error_for_status()?;
+    let download = Download::new(0, CLI_OWNER, &name);
+    let result = print_progress(write_response(response, &path_text, &download), &download)
+        .await
+        .map_err(|e| e.to_string());
+    if result.is_err() {
+        delete_file(path_text).await;
+    }
+    result?;
+    Ok(vec![path])
+}
+
+/// Drive `future` while printing the progress of `download` on one line
+/// Ctrl+C cancels the download
+async fn print_progress<F: Future>(future: F, download: &Download) -> F::Output {
+    let cancel_token = download.cancel_token().clone();
+    let ctrl_c = tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            cancel_token.cancel();
+        }
+    });
+
+    tokio::pin!(future);
+    let output = loop {
+        if let Ok(output) = tokio::time::timeout(PROGRESS_INTERVAL, &mut future).await {
+            break output;
+        }
+        if let Some(info) = download_registry::get(download.id()) {
+            print!("\r{}", info.summary());
+            let _ = std::io::stdout().flush();
+        }
+    };
+    ctrl_c.abort();
+    println!();
+    output
+}
+
+fn prompt(text: &str) -> ResultGram<String> {
+    print!("{text}");
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
@@ -0,0 +1,426 @@
+//! Concurrent download of Telegram media, used by the bot and usable from other tools
+//!
+//! ```ignore
+//! let downloader = Downloader::for_message(client, &message)
+//!     .ok_or("not a document")?
+//!     .writer(tokio::fs::File::create("file.bin").await?)
+//!     .workers(4)
+//!     .cancel_token(token)
+//!     .on_progress(|downloaded, total| println!("{downloaded} of {total}"))
+//!     .build();
+//! let size = downloader.run().await?;
+//! ```
+
+use grammers_client::client::files::MAX_CHUNK_SIZE;
+use grammers_client::grammers_tl_types as tl;
+use grammers_client::types::{Media, Message, PackedChat};
+use grammers_client::{Client, InvocationError};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::SeekFrom;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Parallel requests when `workers` is not set
+const DEFAULT_WORKERS: usize = 4;
+/// How often the message is fetched again for a fresh file reference
+const MAX_REFRESHES: u64 = 5;
+
+/// Called with downloaded and total bytes after every written part
+pub type ProgressCallback = Box<dyn Fn(u64, u64) + Send + Sync>;
+
+#[derive(Debug)]
+pub enum DownloadError {
+    /// The cancel token was cancelled
+    Cancelled,
+    /// Telegram refused a part
+    Telegram(InvocationError),
+    /// Writing to the destination failed
+    Io(std::io::Error),
+    /// A worker panicked
+    Worker(String),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Cancelled => write!(f, "Download Cancelled"),
+            DownloadError::Telegram(e) => write!(f, "Telegram error: {e}"),
+            DownloadError::Io(e) => write!(f, "Write error: {e}"),
+            DownloadError::Worker(e) => write!(f, "Download worker failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+impl From<std::io::Error> for DownloadError {
+    fn from(error: std::io::Error) -> Self {
+        DownloadError::Io(error)
+    }
+}
+
+impl From<InvocationError> for DownloadError {
+    fn from(error: InvocationError) -> Self {
+        DownloadError::Telegram(error)
+    }
+}
+
+/// Settings of a download, `W` is `()` until a writer is given
+pub struct DownloaderBuilder<W> {
+    client: Client,
+    location: tl::enums::InputFileLocation,
+    size: u64,
+    writer: W,
+    workers: usize,
+    cancel_token: CancellationToken,
+    on_progress: Option<ProgressCallback>,
+    /// Chat and id of the message with the file, to refresh an expired file reference
+    source: Option<(PackedChat, i32)>,
+    resume_from: u64,
+    checkpoint: Option<Arc<AtomicU64>>,
+}
+
+impl<W> DownloaderBuilder<W> {
+    /// Size of the file in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Destination, parts can arrive out of order so it must be seekable
+    pub fn writer<V: AsyncWrite + AsyncSeek + Unpin>(self, writer: V) -> DownloaderBuilder<V> {
+        DownloaderBuilder {
+            client: self.client,
+            location: self.location,
+            size: self.size,
+            writer,
+            workers: self.workers,
+            cancel_token: self.cancel_token,
+            on_progress: self.on_progress,
+            source: self.source,
+            resume_from: self.resume_from,
+            checkpoint: self.checkpoint,
+        }
+    }
+
+    /// Number of parts fetched at the same time
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    pub fn cancel_token(mut self, cancel_token: CancellationToken) -> Self {
+        self.cancel_token = cancel_token;
+        self
+    }
+
+    pub fn on_progress(mut self, on_progress: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// Message the file comes from, fetched again when the file reference expires
+    pub fn refetch_from(mut self, chat: PackedChat, message_id: i32) -> Self {
+        self.source = Some((chat, message_id));
+        self
+    }
+
+    /// Continue an interrupted download, bytes before `offset` are already in the writer
+    /// Rounded down to a part, the writer must not be truncated
+    pub fn resume_from(mut self, offset: u64) -> Self {
+        self.resume_from = offset - offset % MAX_CHUNK_SIZE as u64;
+        self
+    }
+
+    /// Kept at the length of the written prefix without gaps, the offset to resume from
+    pub fn checkpoint(mut self, checkpoint: Arc<AtomicU64>) -> Self {
+        self.checkpoint = Some(checkpoint);
+        self
+    }
+}
+
+impl<W: AsyncWrite + AsyncSeek + Unpin> DownloaderBuilder<W> {
+    pub fn build(self) -> Downloader<W> {
+        Downloader {
+            client: self.client,
+            location: self.location,
+            size: self.size,
+            writer: self.writer,
+            workers: self.workers,
+            cancel_token: self.cancel_token,
+            on_progress: self.on_progress,
+            source: self.source,
+            resume_from: self.resume_from,
+            checkpoint: self.checkpoint,
+        }
+    }
+}
+
+/// Downloads one file with several workers, see [`Downloader::builder`]
+pub struct Downloader<W> {
+    client: Client,
+    location: tl::enums::InputFileLocation,
+    size: u64,
+    writer: W,
+    workers: usize,
+    cancel_token: CancellationToken,
+    on_progress: Option<ProgressCallback>,
+    /// Chat and id of the message with the file, to refresh an expired file reference
+    source: Option<(PackedChat, i32)>,
+    resume_from: u64,
+    checkpoint: Option<Arc<AtomicU64>>,
+}
+
+impl Downloader<()> {
+    pub fn builder(
+        client: Client,
+        location: tl::enums::InputFileLocation,
+        size: u64,
+    ) -> DownloaderBuilder<()> {
+        DownloaderBuilder {
+            client,
+            location,
+            size,
+            writer: (),
+            workers: DEFAULT_WORKERS,
+            cancel_token: CancellationToken::new(),
+            on_progress: None,
+            source: None,
+            resume_from: 0,
+            checkpoint: None,
+        }
+    }
+
+    /// Builder for the file of a message, `None` if `media` is not a document
+    pub fn for_media(client: Client, media: &Media) -> Option<DownloaderBuilder<()>> {
+        let size = match media {
+            Media::Document(document) => document.size().max(0) as u64,
+            _ => return None,
+        };
+        Some(Downloader::builder(
+            client,
+            media.to_raw_input_location()?,
+            size,
+        ))
+    }
+
+    /// Like `for_media`, but survives an expired file reference by fetching the message again
+    pub fn for_message(client: Client, message: &Message) -> Option<DownloaderBuilder<()>> {
+        let builder = Downloader::for_media(client, &message.media()?)?;
+        Some(builder.refetch_from(message.chat().pack(), message.id()))
+    }
+}
+
+/// Location used by all workers, replaced when the file reference expires
+struct SharedLocation {
+    location: tl::enums::InputFileLocation,
+    refreshes: u64,
+}
+
+impl<W: AsyncWrite + AsyncSeek + Unpin> Downloader<W> {
+    /// Download the whole file, returns its size including a resumed part
+    pub async fn run(mut self) -> Result<u64, DownloadError> {
+        let (tx, mut rx) = unbounded_channel();
+        let next_part = Arc::new(AtomicU64::new(self.resume_from / MAX_CHUNK_SIZE as u64));
+        let mut tasks: Vec<JoinHandle<Result<(), DownloadError>>> = Vec::new();
+        let shared = Arc::new(Mutex::new(SharedLocation {
+            location: self.location.clone(),
+            refreshes: 0,
+        }));
+
+        // Cancelled with the download or when a worker fails
+        let workers_token = self.cancel_token.child_token();
+        for _ in 0..self.workers {
+            let client = self.client.clone();
+            let shared = shared.clone();
+            let source = self.source;
+            let size = self.size;
+            let next_part = next_part.clone();
+            let tx = tx.clone();
+            let cancel_token = workers_token.clone();
+            let workers_token = workers_token.clone();
+
+            tasks.push(tokio::spawn(async move {
+                let result: Result<(), DownloadError> = async {
+                    let mut retry_offset = None;
+                    let mut dc = None;
+                    loop {
+                        if cancel_token.is_cancelled() {
+                            return Ok(());
+                        }
+                        let offset = match retry_offset.take() {
+                            Some(offset) => offset,
+                            None => {
+                                next_part.fetch_add(1, Ordering::SeqCst) * MAX_CHUNK_SIZE as u64
+                            }
+                        };
+                        if offset >= size {
+                            return Ok(());
+                        }
+                        let (location, refreshes) = {
+                            let shared = shared.lock().await;
+                            (shared.location.clone(), shared.refreshes)
+                        };
+                        let request = &tl::functions::upload::GetFile {
+                            precise: true,
+                            cdn_supported: false,
+                            location,
+                            offset: offset as i64,
+                            limit: MAX_CHUNK_SIZE,
+                        };
+                        let result = match dc {
+                            None => client.invoke(request).await,
+                            Some(dc) => client.invoke_in_dc(request, dc).await,
+                        };
+                        match result {
+                            Ok(tl::enums::upload::File::File(file)) => {
+                                // Only fails when the writer stopped
+                                if tx.send((offset, file.bytes)).is_err() {
+                                    return Ok(());
+                                }
+                            }
+                            Ok(tl::enums::upload::File::CdnRedirect(_)) => {
+                                panic!(
+                                    "API returned File::CdnRedirect even though cdn_supported = false"
+                                );
+                            }
+                            // File is in another data center
+                            Err(InvocationError::Rpc(err)) if err.code == 303 => {
+                                dc = err.value.map(|value| value as i32);
+                                retry_offset = Some(offset);
+                            }
+                            // Parts already written stay, only this one is fetched again
+                            Err(InvocationError::Rpc(err))
+                                if err.name == "FILE_REFERENCE_EXPIRED" =>
+                            {
+                                if !refresh_location(&client, source, &shared, refreshes).await {
+                                    return Err(InvocationError::Rpc(err).into());
+                                }
+                                retry_offset = Some(offset);
+                            }
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                }
+                .await;
+                // The download fails anyway, the other workers stop fetching
+                if result.is_err() {
+                    workers_token.cancel();
+                }
+                result
+            }));
+        }
+        drop(tx);
+
+        let result = self.write_parts(&mut rx).await;
+        if result.is_err() {
+            for task in &tasks {
+                task.abort();
+            }
+            return result;
+        }
+        for task in tasks {
+            task.await
+                .map_err(|e| DownloadError::Worker(e.to_string()))??;
+        }
+        if self.cancel_token.is_cancelled() {
+            return Err(DownloadError::Cancelled);
+        }
+        result
+    }
+
+    async fn write_parts(
+        &mut self,
+        rx: &mut tokio::sync::mpsc::UnboundedReceiver<(u64, Vec<u8>)>,
+    ) -> Result<u64, DownloadError> {
+        let mut position = self.resume_from;
+        let mut downloaded = self.resume_from;
+        // Parts arrive out of order, the ones after a gap wait here for the checkpoint
+        let mut prefix = self.resume_from;
+        let mut after_gap: BTreeMap<u64, u64> = BTreeMap::new();
+        self.save_checkpoint(prefix);
+
+        while let Some((offset, bytes)) = rx.recv().await {
+            if self.cancel_token.is_cancelled() {
+                // What is written must reach the disk to resume from the checkpoint
+                self.writer.flush().await?;
+                return Err(DownloadError::Cancelled);
+            }
+            if offset != position {
+                self.writer.seek(SeekFrom::Start(offset)).await?;
+            }
+            self.writer.write_all(&bytes).await?;
+            position = offset + bytes.len() as u64;
+            downloaded += bytes.len() as u64;
+
+            after_gap.insert(offset, position);
+            while let Some(end) = after_gap.remove(&prefix) {
+                prefix = end;
+            }
+            self.save_checkpoint(prefix);
+            if let Some(on_progress) = &self.on_progress {
+                on_progress(downloaded, self.size);
+            }
+        }
+        self.writer.flush().await?;
+        Ok(downloaded)
+    }
+
+    fn save_checkpoint(&self, prefix: u64) {
+        if let Some(checkpoint) = &self.checkpoint {
+            checkpoint.store(prefix, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Fetch the message again for a fresh file reference, false if that's not possible
+/// Only the first worker that sees `refreshes` fetches, the others use its location
+async fn refresh_location(
+    client: &Client,
+    source: Option<(PackedChat, i32)>,
+    shared: &Mutex<SharedLocation>,
+    refreshes: u64,
+) -> bool {
+    let (chat, message_id) = match source {
+        Some(source) => source,
+        None => return false,
+    };
+    let mut shared = shared.lock().await;
+    if shared.refreshes != refreshes {
+        return true;
+    }
+    if shared.refreshes >= MAX_REFRESHES {
+        log::warn!("File reference of message {message_id} expired too often");
+        return false;
+    }
+
+    let message = match client.get_messages_by_id(chat, &[message_id]).await {
+        Ok(mut messages) => messages.pop().flatten(),
+        Err(e) => {
+            log::warn!("Failed to fetch message {message_id} again: {e}");
+            return false;
+        }
+    };
+    match message
+        .and_then(|message| message.media())
+        .and_then(|media| media.to_raw_input_location())
+    {
+        Some(location) => {
+            log::info!(
+                "File reference of message {message_id} expired, continuing with a fresh one"
+            );
+            shared.location = location;
+            shared.refreshes += 1;
+            true
+        }
+        None => {
+            log::warn!("Message {message_id} no longer has the file");
+            false
+        }
+    }
+}
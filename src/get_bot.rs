@@ -1,31 +1,102 @@
 use crate::app_config::AppConfig;
+use crate::utils::helper::send_message_to_user;
+use crate::utils::shutdown;
 use crate::ResultGram;
 use grammers_client::session::Session;
 use grammers_client::{Client, Config, InitParams};
+use std::time::{Duration, Instant};
 
-const BOT_SESSION_FILE: &str = "bot.session";
+/// Wait before the first reconnect, doubled after every failure
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
 
-// Create session for this bot
-pub async fn get_bot(config: AppConfig) -> ResultGram<Client> {
+/// Connect with the session in `session_file`, without signing in
+/// With `catch_up` updates missed since the session was saved are delivered first
+pub async fn connect(config: &AppConfig, session_file: &str, catch_up: bool) -> ResultGram<Client> {
     let client = Client::connect(Config {
-        session: Session::load_file_or_create(BOT_SESSION_FILE)?,
+        session: Session::load_file_or_create(session_file)?,
         api_id: config.api_id,
         api_hash: config.api_hash.to_string().clone(),
         params: InitParams {
-            catch_up: false,
+            catch_up,
             flood_sleep_threshold: 60,
             ..Default::default()
         },
     })
     .await?;
     log::info!("Bot Connected!");
+    Ok(client)
+}
+
+// Create session for this bot
+pub async fn get_bot(config: AppConfig, session_file: &str, catch_up: bool) -> ResultGram<Client> {
+    let client = connect(&config, session_file, catch_up).await?;
 
     if !client.is_authorized().await? {
         log::info!("Signing in...");
         client.bot_sign_in(config.bot_token.as_str()).await?;
-        client.session().save_to_file(BOT_SESSION_FILE)?;
+        client.session().save_to_file(session_file)?;
         log::info!("Signed in!");
     }
 
     Ok(client)
 }
+
+/// Connect again after the update stream failed, retrying with backoff
+/// The new client catches up on updates missed meanwhile, None if shutdown was requested
+/// Updates handled before can come again, callers skip them with `handled::first_time`
+pub async fn reconnect(old: &Client, session_file: &str) -> Option<Client> {
+    // The saved session remembers the last update the old client got
+    old.sync_update_state();
+    if let Err(e) = old.session().save_to_file(session_file) {
+        log::error!("Failed to save session: {e}");
+    }
+    let offline_since = Instant::now();
+    let mut delay = RECONNECT_DELAY;
+    let mut alerted = false;
+    loop {
+        tokio::select! {
+            _ = shutdown::signal() => return None,
+            _ = tokio::time::sleep(delay) => {}
+        }
+        let config = AppConfig::current();
+        let alert_after = Duration::from_secs(config.offline_alert_after);
+
+        log::info!("Reconnecting to Telegram");
+        let result = get_bot(config.clone(), session_file, true)
+            .await
+            .map_err(|e| e.to_string());
+        let offline = offline_since.elapsed();
+        match result {
+            Ok(bot) => {
+                log::info!("Reconnected after {} sec", offline.as_secs());
+                if offline >= alert_after {
+                    let text = format!(
+                        "Bot was offline for {} min, reconnected",
+                        offline.as_secs() / 60
+                    );
+                    let error = send_message_to_user(bot.clone(), config.user_id, text)
+                        .await
+                        .err()
+                        .map(|e| e.to_string());
+                    if let Some(error) = error {
+                        log::error!("Failed to tell admin the bot reconnected: {error}");
+                    }
+                }
+                return Some(bot);
+            }
+            Err(error) => {
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                log::warn!(
+                    "Failed to reconnect: {error}, retrying in {} sec",
+                    delay.as_secs()
+                );
+            }
+        }
+        // The admin can only be messaged once the bot is back, the log has it right away
+        if !alerted && offline >= alert_after {
+            alerted = true;
+            log::error!("Bot is offline for {} sec", offline.as_secs());
+        }
+    }
+}
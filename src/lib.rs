@@ -0,0 +1,2 @@
+//! Download engine of the bot, for tools that want Telegram downloads without the bot
+pub mod downloader;
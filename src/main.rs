@@ -1,52 +1,172 @@
 mod app_config;
+mod cli;
 mod get_bot;
 mod message_handler;
 mod utils;
 
-use app_config::AppConfig;
-use get_bot::get_bot;
-use grammers_client::Client;
+use app_config::{watch_config, AppConfig};
+use clap::Parser;
+use cli::{Cli, Command};
+use get_bot::{get_bot, reconnect};
+use grammers_client::{Client, Update};
 use message_handler::default_handler::handle_update;
+use message_handler::resume_handler::resume_pending;
 use simple_logger::SimpleLogger;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::runtime;
-use utils::{custom_result::ResultGram, helper::send_message_to_user};
+use utils::self_check::{check_admin, check_environment, report, CheckStatus};
+use utils::{
+    control, conversation::route, custom_result::ResultGram, handled, helper::send_message_to_user,
+    metrics, shutdown,
+};
+
+/// Time after startup the admin gets the summary of messages caught up on
+const CATCH_UP_SUMMARY_DELAY: Duration = Duration::from_secs(30);
+/// How often handled message ids are written to `HANDLED_FILE`
+const HANDLED_SAVE_INTERVAL: Duration = Duration::from_secs(10);
 
 fn main() -> ResultGram<()> {
+    let cli = Cli::parse();
+
+    // The level is set from config, it can change on reload
     SimpleLogger::new()
-        .with_level(log::LevelFilter::Info)
+        .with_level(log::LevelFilter::Trace)
         .init()
         .unwrap();
+    log::set_max_level(log::LevelFilter::Info);
 
     runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap()
-        .block_on(run_bot())
+        .block_on(async {
+            // Config can come from config.toml alone, .env is optional
+            dotenv::dotenv().ok();
+            let config = AppConfig::init(cli.config)?;
+            let session_file = cli.session.clone().unwrap_or(config.session_file.clone());
+
+            match cli.command.unwrap_or(Command::Run) {
+                Command::Run => run_bot(config, &session_file).await,
+                Command::Login => cli::login(&config, cli.session.as_deref()).await,
+                Command::Check => cli::run_check(&config, &session_file).await,
+                Command::Download { link, output } => {
+                    cli::download(&config, &session_file, &link, output).await
+                }
+                Command::Queue { action } => cli::queue(&config, action).await,
+            }
+        })
 }
 
-async fn run_bot() -> ResultGram<()> {
-    dotenv::dotenv().expect("please add .env file");
-    let config = AppConfig::from_env().unwrap();
+async fn run_bot(config: AppConfig, session_file: &str) -> ResultGram<()> {
+    watch_config();
+    // The command line asks this socket for running downloads
+    if let Err(e) = control::serve(&config.control_socket) {
+        log::error!("Failed to open {}: {e}", config.control_socket);
+    }
+    if let Some(address) = &config.metrics_address {
+        let error = metrics::serve(address).await.err().map(|e| e.to_string());
+        if let Some(error) = error {
+            log::error!("Failed to serve metrics on {address}: {error}");
+        }
+    }
+
+    // Problems are only reported, the bot still runs with what works
+    let mut checks = check_environment(&config, session_file).await;
 
+    // Messages older than this arrived while the bot was offline
+    let started_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or(0);
     log::info!("Connecting to Telegram");
-    let bot: Client = get_bot(config.clone()).await?;
+    let mut bot: Client = get_bot(config.clone(), session_file, config.catch_up).await?;
+    checks.push(check_admin(&bot, &config).await);
+
+    let check_report = report(&checks);
+    let problems: Vec<String> = checks
+        .iter()
+        .filter(|check| check.status != CheckStatus::Ok)
+        .map(|check| check.line())
+        .collect();
+    if problems.is_empty() {
+        log::info!("Self-check:\n{check_report}");
+    } else {
+        log::warn!("Self-check:\n{check_report}");
+    }
+
+    let mut started = "Bot Started /help".to_string();
+    if !problems.is_empty() {
+        started = format!(
+            "{started}\n\nSelf-check found problems:\n{}",
+            problems.join("\n")
+        );
+    }
+    let error = send_message_to_user(bot.clone(), config.user_id, started)
+        .await
+        .err()
+        .map(|e| e.to_string());
+    if let Some(error) = error {
+        log::error!("Failed to tell admin the bot started: {error}");
+    }
+    resume_pending(bot.clone(), &config);
 
-    send_message_to_user(bot.clone(), config.user_id, "Bot Started /help").await?;
+    // Missed updates come first, the admin gets a summary once they are through
+    let summary_at = tokio::time::Instant::now() + CATCH_UP_SUMMARY_DELAY;
+    let mut summary_pending = config.catch_up;
+    let mut offline_messages = 0;
+    let mut save_handled = tokio::time::interval(HANDLED_SAVE_INTERVAL);
 
     loop {
         tokio::select! {
-            _ = tokio::signal::ctrl_c() => {
-                log::info!("Shutting down...");
+            _ = shutdown::signal() => {
                 break;
             }
+            _ = tokio::time::sleep_until(summary_at), if summary_pending => {
+                summary_pending = false;
+                if offline_messages > 0 {
+                    let text = format!("Processed {offline_messages} messages received while offline");
+                    log::info!("{text}");
+                    let error = send_message_to_user(bot.clone(), config.user_id, text)
+                        .await
+                        .err()
+                        .map(|e| e.to_string());
+                    if let Some(error) = error {
+                        log::error!("Failed to send catch up summary to admin: {error}");
+                    }
+                }
+            }
+            _ = save_handled.tick(), if config.catch_up => {
+                handled::save(&config.handled_file).await;
+            }
             result = bot.next_update() => {
                 let update = match result {
                     Ok(update) => update,
+                    // Running downloads keep going, new updates come from the new client
                     Err(e) => {
                         log::error!("Error getting update: {}", e);
-                        break;
+                        match reconnect(&bot, session_file).await {
+                            Some(client) => {
+                                bot = client;
+                                continue;
+                            }
+                            None => break,
+                        }
                     }
                 };
+                if let Update::NewMessage(message) = &update {
+                    // Catch up at startup and after every reconnect can deliver a message again
+                    if !handled::first_time(&config, message) {
+                        continue;
+                    }
+                    if !message.outgoing() && message.date().timestamp() < started_at {
+                        offline_messages += 1;
+                    }
+                }
+                // Replies to pending prompts go to the handler waiting for them
+                let update = match route(update) {
+                    Some(update) => update,
+                    None => continue,
+                };
                 let bot_handler = bot.clone();
                 tokio::spawn(async move {
                     if let Err(e) = handle_update(bot_handler, update).await {
@@ -56,5 +176,15 @@ async fn run_bot() -> ResultGram<()> {
             }
         };
     }
+    let grace_period = AppConfig::current().shutdown_grace_period;
+    shutdown::shutdown(Duration::from_secs(grace_period)).await;
+    if config.catch_up {
+        handled::save(&config.handled_file).await;
+    }
+    // Catch up after restart starts from the last update handled here
+    bot.sync_update_state();
+    if let Err(e) = bot.session().save_to_file(session_file) {
+        log::error!("Failed to save session: {e}");
+    }
     Ok(())
 }
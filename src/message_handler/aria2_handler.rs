@@ -0,0 +1,165 @@
+use grammers_client::types::Message;
+use std::fs::{canonicalize, create_dir_all};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::app_config::AppConfig;
+use crate::utils::aria2::Aria2Client;
+use crate::utils::audit::{audit, AuditEntry};
+use crate::utils::bot_error::BotError;
+use crate::utils::custom_result::ResultGram;
+use crate::utils::download_registry::Download;
+use crate::utils::helper::{get_directory, get_document};
+use crate::utils::hooks::download_finished;
+use crate::utils::pending::{self, PendingJob, PendingKind};
+use crate::utils::progress::ProgressReporter;
+use crate::utils::quota::{admit, ActiveDownload};
+
+/// aria2 follows redirects and resolves hosts itself, bypassing `UrlPolicy`
+/// so plain links are never sent to it, only torrents
+pub enum Aria2Job {
+    Magnet(String),
+    Torrent(Vec<u8>),
+}
+
+/// Send magnet or torrent job to aria2 and follow it until done
+pub async fn download_with_aria2(
+    message: Message,
+    aria2: Aria2Client,
+    job: Aria2Job,
+) -> ResultGram<()> {
+    let quota = match admit(&message, &job_name(&job, &message), None).await? {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+    let directory = match get_directory(message.clone()).await? {
+        Some(directory) => directory,
+        None => return Ok(()),
+    };
+    if let Err(e) = create_dir_all(&directory) {
+        let error = BotError::from_io(e, &directory);
+        log::error!("Failed to create download directory: {error}");
+        error
+            .reply_to(&message, AppConfig::current().language)
+            .await?;
+        return Ok(());
+    }
+    // aria2 resolves relative paths from its own working directory
+    let directory = canonicalize(&directory)?;
+
+    let gid = match &job {
+        Aria2Job::Magnet(uri) => aria2.add_uri(uri, &directory).await,
+        Aria2Job::Torrent(torrent) => aria2.add_torrent(torrent, &directory).await,
+    }
+    .map_err(BotError::from);
+    let gid = match gid {
+        Ok(gid) => gid,
+        Err(error) => {
+            log::error!("aria2 rejected job: {error}");
+            error
+                .reply_to(&message, AppConfig::current().language)
+                .await?;
+            return Ok(());
+        }
+    };
+    log::info!("aria2 job {gid} to {}", directory.display());
+    let name = job_name(&job, &message);
+    follow_aria2(message, aria2, gid, name, directory, quota, 0).await
+}
+
+/// Follow aria2 job `gid` until done and reply with the result
+/// Also used to follow a job again after restart, `completed` bytes were counted before
+pub async fn follow_aria2(
+    message: Message,
+    aria2: Aria2Client,
+    gid: String,
+    name: String,
+    directory: PathBuf,
+    quota: ActiveDownload,
+    completed: u64,
+) -> ResultGram<()> {
+    let config = AppConfig::current();
+    let entry = AuditEntry::from_message(&message, "download")
+        .file_name(&name)
+        .destination(&directory.to_string_lossy());
+    audit(&config, entry.clone().result("started"));
+
+    let download = Download::register(&message, &name, quota);
+    download.progress().resume_at(completed);
+    let cancel_token = download.cancel_token();
+    let progress = download.progress();
+    let on_progress = |name: &str, downloaded: u64, total: u64| {
+        progress.set(name, downloaded, total);
+    };
+    let keep_job = || download.is_interrupted();
+
+    let start_time = Instant::now();
+    let mut reporter = ProgressReporter::for_chat(&message, &download, &config).await?;
+    let result = reporter
+        .run(aria2.wait(&gid, cancel_token, &keep_job, &on_progress))
+        .await
+        .map_err(|e| download.error(e));
+
+    // aria2 keeps downloading while the bot is stopped, the job is followed again after restart
+    if let Err(BotError::Interrupted) = result {
+        reporter.interrupt().await;
+        let (name, completed, _) = progress.get();
+        drop(download);
+        let kind = PendingKind::Aria2 {
+            gid: gid.clone(),
+            completed,
+        };
+        let path = directory.to_string_lossy();
+        if let Err(e) = pending::add(&config, PendingJob::new(&message, &name, &path, kind)) {
+            log::error!("Failed to save interrupted aria2 job {gid}: {e}");
+        }
+        audit(
+            &config,
+            entry.duration(start_time.elapsed()).result("interrupted"),
+        );
+        return Ok(());
+    }
+    reporter.finish(&result).await;
+
+    let mut entry = entry
+        .duration(start_time.elapsed())
+        .outcome(&result, download.is_cancelled_by_user());
+    if let Ok(status) = &result {
+        entry = entry.file_name(&status.name).size(status.total);
+    }
+    audit(&config, entry);
+
+    match result {
+        Ok(status) => {
+            for file in &status.files {
+                download_finished(&config, &message, Path::new(file), start_time.elapsed()).await;
+            }
+            let stored_at = match status.files.as_slice() {
+                [file] => file.clone(),
+                _ => format!("{}/{}", directory.display(), status.name),
+            };
+            message
+                .reply(format!(
+                    "Download Completed in {} sec \nStored at: {}",
+                    start_time.elapsed().as_secs(),
+                    stored_at
+                ))
+                .await?;
+        }
+        Err(error) => {
+            log::error!("aria2 job {gid} failed: {error}");
+            error.reply_to(&message, config.language).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Magnet link or torrent file name, until aria2 knows the real name
+fn job_name(job: &Aria2Job, message: &Message) -> String {
+    match job {
+        Aria2Job::Magnet(uri) => uri.clone(),
+        Aria2Job::Torrent(_) => get_document(message.clone())
+            .map(|document| document.name().to_string())
+            .unwrap_or_else(|| "torrent".to_string()),
+    }
+}
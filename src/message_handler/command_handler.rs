@@ -1,11 +1,33 @@
-use std::process::Command;
+use std::collections::HashMap;
+use std::fs::create_dir_all;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use grammers_client::types::{Chat, Message};
-use grammers_client::Client;
+use grammers_client::{button, reply_markup, Client, InputMessage};
 use local_ip_address::local_ip;
+use tokio::process::Command;
 
+use crate::app_config::AppConfig;
+use crate::message_handler::history_handler::{handle_find, handle_recent};
+use crate::message_handler::user_handler::handle_users_command;
+use crate::utils::access_control::{Access, AccessControl, Role};
+use crate::utils::audit::{audit, recent_entries, AuditEntry};
+use crate::utils::bot_error::BotError;
+use crate::utils::callback_data::CallbackData;
 use crate::utils::custom_result::ResultGram;
-use crate::utils::helper::{get_custom_file_name, get_directory};
+use crate::utils::download_registry::{self, Download, DownloadInfo};
+use crate::utils::external_tool::is_installed;
+use crate::utils::gdrive::{
+    download_with_gdown, parse_drive_link, DriveClient, DriveLink, GDOWN_PROGRAM,
+};
+use crate::utils::helper::{ask_query, get_directory, send_message_to_user};
+use crate::utils::hooks::download_finished;
+use crate::utils::pending::{self, PendingJob, PendingKind};
+use crate::utils::progress::ProgressReporter;
+use crate::utils::quota::{admit, ActiveDownload};
+use crate::utils::url_policy::UrlPolicy;
 
 const START_COMMAND: &str = "/start";
 const IP_COMMAND: &str = "/ip";
@@ -13,12 +35,89 @@ const INFO_COMMAND: &str = "/info";
 const REBOOT_COMMAND: &str = "/reboot";
 const HELP_COMMAND: &str = "/help";
 const GDOWN_COMMAND: &str = "/gdown";
+const USERS_COMMAND: &str = "/users";
+const AUDIT_COMMAND: &str = "/audit";
+const DOWNLOADS_COMMAND: &str = "/downloads";
+const RECENT_COMMAND: &str = "/recent";
+const FIND_COMMAND: &str = "/find";
+const AUDIT_DEFAULT_ENTRIES: usize = 10;
+const AUDIT_MAX_ENTRIES: usize = 50;
+
+/// Commands that change the machine, admin only and confirmed with a button
+const PROTECTED_COMMANDS: [&str; 1] = [REBOOT_COMMAND];
+/// Enough for the confirmation to reach Telegram before the network goes down
+const REBOOT_DELAY: Duration = Duration::from_secs(5);
+
+lazy_static::lazy_static! {
+    /// Last confirmed run of each protected command, for the cool-down
+    static ref LAST_RUN: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Lowest role allowed to run the command
+pub fn required_role(command: &str) -> Role {
+    if command.contains(GDOWN_COMMAND) {
+        return Role::Downloader;
+    }
+    if command.starts_with(FIND_COMMAND) {
+        return Role::Downloader;
+    }
+    if PROTECTED_COMMANDS.contains(&command) || command.starts_with(AUDIT_COMMAND) {
+        return Role::Admin;
+    }
+    match command {
+        USERS_COMMAND => Role::Admin,
+        IP_COMMAND | INFO_COMMAND | DOWNLOADS_COMMAND | RECENT_COMMAND => Role::Downloader,
+        _ => Role::ReadOnly,
+    }
+}
 
 pub async fn handle_command(bot: Client, chat: Chat, message: Message) -> ResultGram<()> {
     let command: &str = message.text();
 
     if command.contains(GDOWN_COMMAND) {
-        download_gdrive(bot.clone(), message.clone()).await?;
+        download_gdrive(message.clone()).await?;
+        return Ok(());
+    }
+
+    if PROTECTED_COMMANDS.contains(&command) {
+        handle_protected_command(bot, message).await?;
+        return Ok(());
+    }
+
+    if command == USERS_COMMAND {
+        let config = AppConfig::current();
+        handle_users_command(message, &config).await?;
+        return Ok(());
+    }
+
+    if command == DOWNLOADS_COMMAND {
+        let config = AppConfig::current();
+        handle_downloads(message, &config).await?;
+        return Ok(());
+    }
+
+    if command == RECENT_COMMAND {
+        let config = AppConfig::current();
+        handle_recent(message, &config).await?;
+        return Ok(());
+    }
+
+    if command.starts_with(FIND_COMMAND) {
+        let text = command.trim_start_matches(FIND_COMMAND).trim().to_string();
+        if text.is_empty() {
+            message
+                .reply(format!("Usage: {FIND_COMMAND} <text>"))
+                .await?;
+            return Ok(());
+        }
+        let config = AppConfig::current();
+        handle_find(message, &config, &text).await?;
+        return Ok(());
+    }
+
+    if command.starts_with(AUDIT_COMMAND) {
+        let config = AppConfig::current();
+        handle_audit(message, &config).await?;
         return Ok(());
     }
 
@@ -26,8 +125,7 @@ pub async fn handle_command(bot: Client, chat: Chat, message: Message) -> Result
     let response: String = match command {
         START_COMMAND => handle_start(chat.clone()),
         IP_COMMAND => handle_ip(),
-        INFO_COMMAND => handle_system_info(),
-        REBOOT_COMMAND => handle_reboot(),
+        INFO_COMMAND => handle_system_info(&message, &AppConfig::current()),
         _ => handle_help(chat.clone()),
     };
     message.reply(response).await?;
@@ -43,7 +141,12 @@ fn handle_help(chat: Chat) -> String {
         {REBOOT_COMMAND} : To reboot the machine\n\
         {INFO_COMMAND}: To get system information\n\
         {HELP_COMMAND}: To get help\n\
-        {GDOWN_COMMAND}: To download gdrive files\n\
+        {GDOWN_COMMAND}: To download gdrive files or folders\n\
+        {USERS_COMMAND}: To manage users\n\
+        {AUDIT_COMMAND} [n]: To see the last n actions\n\
+        {DOWNLOADS_COMMAND}: To see running downloads\n\
+        {RECENT_COMMAND}: To see finished downloads\n\
+        {FIND_COMMAND} <text>: To search finished downloads by name\n\
         \nor send files to download"
     )
     .to_string();
@@ -59,74 +162,344 @@ fn handle_ip() -> String {
     return format!("Here is your ip: {}", my_local_ip).to_string();
 }
 
-fn handle_system_info() -> String {
+/// Downloads are listed like `/downloads`, users only see their own
+fn handle_system_info(message: &Message, config: &AppConfig) -> String {
     let fs_stats = fs2::statvfs("/").unwrap();
     let total_space = fs_stats.total_space() as f64 / 1073741824.0;
     let free_space: f64 = fs_stats.available_space() as f64 / 1073741824.0;
-    return format!(
+    let mut info = format!(
         "Here is your system info: \nTotal Space: {:.1} GB \nFree Space: {:.1} GB",
         total_space, free_space
-    )
-    .to_string();
-}
+    );
 
-fn handle_reboot() -> String {
-    if cfg!(target_os = "linux") {
-        let output = Command::new("sudo")
-            .arg("reboot")
-            .output()
-            .expect("failed to execute process");
-        return String::from_utf8_lossy(&output.stdout).to_string();
+    let downloads = visible_downloads(message, config);
+    info.push_str(&format!("\nRunning Downloads: {}", downloads.len()));
+    for download in downloads {
+        info.push_str(&format!("\n{}. {}", download.id, download.summary()));
     }
-    return "Not supported yet".to_string();
+    info
 }
 
-/// If gdown installed in system
-pub async fn download_gdrive(bot: Client, message: Message) -> ResultGram<()> {
-    let gdrive_id = message.text().replace(GDOWN_COMMAND, "").trim().to_string();
+/// Ask for confirmation, check the cool-down and audit every step
+async fn handle_protected_command(bot: Client, message: Message) -> ResultGram<()> {
+    let config = AppConfig::current();
+    let command = message.text().to_string();
+    let entry = AuditEntry::from_message(&message, &command);
 
-    log::info!("Grdive Download: {gdrive_id}");
+    if let Some(wait) = cooldown_left(&command, config.system_command_cooldown) {
+        audit(&config, entry.clone().result("cool-down"));
+        message
+            .reply(format!(
+                "{command} was run recently, try again in {} sec",
+                wait.as_secs()
+            ))
+            .await?;
+        return Ok(());
+    }
+
+    let options = vec!["Yes, run it".to_string(), "Cancel".to_string()];
+    let question = format!("Are you sure you want to run {command}?");
+    let choice = ask_query(message.clone(), &question, options).await?;
+    if choice != Some(0) {
+        audit(&config, entry.clone().result("cancelled"));
+        message.reply(format!("{command} cancelled")).await?;
+        return Ok(());
+    }
 
-    if gdrive_id.is_empty() {
+    // Another admin may have confirmed while we were waiting
+    if let Some(wait) = start_cooldown(&command, config.system_command_cooldown) {
+        audit(&config, entry.clone().result("cool-down"));
         message
-            .reply("Please send a valid gdrive link or id")
+            .reply(format!(
+                "{command} was run recently, try again in {} sec",
+                wait.as_secs()
+            ))
             .await?;
         return Ok(());
     }
+    audit(&config, entry.clone().result("confirmed"));
+
+    match command.as_str() {
+        REBOOT_COMMAND => handle_reboot(bot, message, config).await?,
+        _ => log::error!("No handler for protected command {command}"),
+    }
+    Ok(())
+}
 
-    let directory_result: Option<String> = get_directory(bot.clone(), message.clone()).await?;
-    if directory_result.is_none() {
+/// Running downloads of the sender, the admin sees everyone's
+fn visible_downloads(message: &Message, config: &AppConfig) -> Vec<DownloadInfo> {
+    let user_id = message.sender().map(|sender| sender.id()).unwrap_or(0);
+    let is_admin = matches!(
+        AccessControl::from_config(config).access_of(user_id),
+        Access::Allowed(Role::Admin)
+    );
+    download_registry::list()
+        .into_iter()
+        .filter(|download| is_admin || download.owner_id == user_id)
+        .collect()
+}
+
+/// Running downloads with a Cancel button each, admin sees downloads of everyone
+async fn handle_downloads(message: Message, config: &AppConfig) -> ResultGram<()> {
+    let downloads = visible_downloads(&message, config);
+    if downloads.is_empty() {
+        message.reply("No downloads running").await?;
         return Ok(());
     }
 
-    let media_name = match get_custom_file_name(bot.clone(), message.clone()).await? {
-        Some(name) => name,
+    let mut lines: Vec<String> = Vec::new();
+    let mut buttons: Vec<Vec<button::Inline>> = Vec::new();
+    for (index, download) in downloads.iter().enumerate() {
+        lines.push(format!(
+            "{}. {}\nby {}",
+            index + 1,
+            download.summary(),
+            download.owner_name
+        ));
+        let data = CallbackData::CancelDownload {
+            download_id: download.id,
+        };
+        buttons.push(vec![button::inline(
+            format!("Cancel {}", index + 1),
+            data.encode(),
+        )]);
+    }
+    let reply = InputMessage::text(lines.join("\n\n")).reply_markup(&reply_markup::inline(buttons));
+    message.reply(reply).await?;
+    Ok(())
+}
+
+/// Show the newest audit entries, `/audit 20` for more
+async fn handle_audit(message: Message, config: &AppConfig) -> ResultGram<()> {
+    let count = message
+        .text()
+        .trim_start_matches(AUDIT_COMMAND)
+        .trim()
+        .parse::<usize>()
+        .unwrap_or(AUDIT_DEFAULT_ENTRIES)
+        .clamp(1, AUDIT_MAX_ENTRIES);
+    let entries = recent_entries(config, count);
+    if entries.is_empty() {
+        message.reply("Audit log is empty").await?;
+        return Ok(());
+    }
+    let lines: Vec<String> = entries.iter().map(|entry| entry.summary()).collect();
+    message
+        .reply(format!(
+            "Last {} actions:\n{}",
+            lines.len(),
+            lines.join("\n")
+        ))
+        .await?;
+    Ok(())
+}
+
+/// Time left before `command` can run again
+fn cooldown_left(command: &str, cooldown: u64) -> Option<Duration> {
+    let last_run = *LAST_RUN.lock().unwrap().get(command)?;
+    Duration::from_secs(cooldown).checked_sub(last_run.elapsed())
+}
+
+/// Mark `command` as run, returns time left instead if it's still cooling down
+fn start_cooldown(command: &str, cooldown: u64) -> Option<Duration> {
+    let mut last_run = LAST_RUN.lock().unwrap();
+    if let Some(time) = last_run.get(command) {
+        if let Some(wait) = Duration::from_secs(cooldown).checked_sub(time.elapsed()) {
+            return Some(wait);
+        }
+    }
+    last_run.insert(command.to_string(), Instant::now());
+    None
+}
+
+/// Reply first, then reboot after `REBOOT_DELAY`
+async fn handle_reboot(bot: Client, message: Message, config: AppConfig) -> ResultGram<()> {
+    if !cfg!(target_os = "linux") {
+        message.reply("Not supported yet").await?;
+        return Ok(());
+    }
+    message
+        .reply(format!("Rebooting in {} sec", REBOOT_DELAY.as_secs()))
+        .await?;
+
+    let entry = AuditEntry::from_message(&message, REBOOT_COMMAND);
+    tokio::spawn(async move {
+        tokio::time::sleep(REBOOT_DELAY).await;
+        let error = match Command::new("sudo").arg("reboot").output().await {
+            Ok(output) if output.status.success() => return,
+            Ok(output) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            Err(e) => e.to_string(),
+        };
+        log::error!("Reboot failed: {error}");
+        audit(&config, entry.result(&format!("failed: {error}")));
+        let error = send_message_to_user(bot, config.user_id, format!("Reboot failed: {error}"))
+            .await
+            .err()
+            .map(|e| e.to_string());
+        if let Some(error) = error {
+            log::error!("Failed to tell admin: {error}");
+        }
+    });
+    Ok(())
+}
+
+/// Download Google Drive files and folders, from share links or ids
+pub async fn download_gdrive(message: Message) -> ResultGram<()> {
+    let gdrive_link = message.text().replace(GDOWN_COMMAND, "").trim().to_string();
+
+    log::info!("Grdive Download: {gdrive_link}");
+
+    let link = match parse_drive_link(&gdrive_link) {
+        Some(link) => link,
+        None => {
+            message
+                .reply("Please send a valid gdrive link or id")
+                .await?;
+            return Ok(());
+        }
+    };
+    let quota = match admit(&message, &gdrive_link, None).await? {
+        Some(quota) => quota,
         None => return Ok(()),
     };
-    let path = format!("{}/{media_name}", directory_result.unwrap());
 
-    let reply_message = message.reply("Starting GoogleDrive download").await?;
+    let directory = match get_directory(message.clone()).await? {
+        Some(directory) => PathBuf::from(directory),
+        None => return Ok(()),
+    };
+    if let Err(e) = create_dir_all(&directory) {
+        let error = BotError::from_io(e, &directory.to_string_lossy());
+        log::error!("Failed to create download directory: {error}");
+        error
+            .reply_to(&message, AppConfig::current().language)
+            .await?;
+        return Ok(());
+    }
+
+    download_drive_to(message, gdrive_link, link, directory, quota).await
+}
 
-    // Run a command and capture the output
-    let output = Command::new("gdown")
-        .arg(gdrive_id)
-        .arg("-O")
-        .arg(path)
-        .output()
-        .expect("Failed to execute command");
+/// Download Drive `link` into `directory` and reply with the result
+/// Also used to start a download interrupted by shutdown again
+pub async fn download_drive_to(
+    message: Message,
+    gdrive_link: String,
+    link: DriveLink,
+    directory: PathBuf,
+    quota: ActiveDownload,
+) -> ResultGram<()> {
+    let config = AppConfig::current();
+    let drive = DriveClient::new(UrlPolicy::from_config(&config));
 
-    // Convert the output to a String
-    let stderr = String::from_utf8_lossy(&output.stderr);
+    let mut download = Download::register(&message, &gdrive_link, quota);
+    let cancel_token = download.cancel_token();
+    let progress = download.progress();
+    let on_progress = |name: &str, downloaded: u64, total: u64| {
+        progress.set(name, downloaded, total);
+    };
 
-    reply_message.delete().await?;
+    let entry = AuditEntry::from_message(&message, "download")
+        .file_name(&gdrive_link)
+        .destination(&directory.to_string_lossy());
+    audit(&config, entry.clone().result("started"));
 
-    // Print the command's output
-    if !stderr.is_empty() && !stderr.contains("█") {
-        eprintln!("Error: {}", stderr);
-        message.reply(format!("Process Failed: {}", stderr)).await?;
-    } else {
-        message.reply(format!("Process Completed")).await?;
+    let start_time = std::time::Instant::now();
+    let mut reporter = ProgressReporter::for_chat(&message, &download, &config).await?;
+    let mut result: Result<Vec<PathBuf>, BotError> = match &link {
+        DriveLink::File(id) => reporter
+            .run(drive.download_file(id, &directory, None, cancel_token, &on_progress))
+            .await
+            .map(|path| vec![path]),
+        DriveLink::Folder(id) => {
+            reporter
+                .run(drive.download_folder(id, &directory, cancel_token, &on_progress))
+                .await
+        }
     }
+    .map_err(|e| download.error(e));
 
-    return Ok(());
+    // Drive pages change from time to time, gdown can still manage if installed
+    if result.is_err() && !cancel_token.is_cancelled() && is_installed(GDOWN_PROGRAM) {
+        log::warn!("Native Drive download failed, retrying with gdown");
+        let timeout = Duration::from_secs(config.external_tool_timeout);
+        result = reporter
+            .run(download_with_gdown(
+                &link,
+                &directory,
+                timeout,
+                cancel_token,
+                &on_progress,
+            ))
+            .await
+            .map_err(|e| download.error(e));
+    }
+
+    // Partial files are removed on cancel, the link is downloaded again after restart
+    if result.is_err() && download.is_interrupted() {
+        reporter.interrupt().await;
+        download.discard_usage();
+        drop(download);
+        let kind = PendingKind::Drive {
+            link: gdrive_link.clone(),
+        };
+        let path = directory.to_string_lossy();
+        if let Err(e) = pending::add(
+            &config,
+            PendingJob::new(&message, &gdrive_link, &path, kind),
+        ) {
+            log::error!("Failed to save interrupted download {gdrive_link}: {e}");
+        }
+        audit(
+            &config,
+            entry.duration(start_time.elapsed()).result("interrupted"),
+        );
+        return Ok(());
+    }
+    reporter.finish(&result).await;
+
+    let mut entry = entry
+        .duration(start_time.elapsed())
+        .outcome(&result, download.is_cancelled_by_user());
+    if let Ok(files) = &result {
+        let size = files
+            .iter()
+            .filter_map(|file| file.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        entry = entry.size(size);
+    }
+    audit(&config, entry);
+    if let Ok(files) = &result {
+        for file in files {
+            download_finished(&config, &message, file, start_time.elapsed()).await;
+        }
+    }
+
+    match result {
+        Ok(files) if files.len() == 1 => {
+            message
+                .reply(format!(
+                    "Download Completed in {} sec \nStored at: {}",
+                    start_time.elapsed().as_secs(),
+                    files[0].display()
+                ))
+                .await?;
+        }
+        Ok(files) => {
+            message
+                .reply(format!(
+                    "Downloaded {} files in {} sec \nStored at: {}",
+                    files.len(),
+                    start_time.elapsed().as_secs(),
+                    directory.display()
+                ))
+                .await?;
+        }
+        Err(error) => {
+            log::error!("Gdrive download failed: {error}");
+            error.reply_to(&message, config.language).await?;
+        }
+    }
+
+    Ok(())
 }
@@ -1,12 +1,24 @@
+use crate::app_config::AppConfig;
+use crate::message_handler::command_handler::required_role;
 use crate::message_handler::document_handler::handle_document;
 use crate::message_handler::query_handler::handle_query;
+use crate::message_handler::user_handler::request_access;
 use crate::message_handler::{command_handler::handle_command, url_handler::handle_url};
-use crate::utils::custom_result::ResultUpdate;
+use crate::utils::access_control::{Access, AccessControl, Role};
+use crate::utils::audit::{audit, AuditEntry};
+use crate::utils::custom_result::{ResultGram, ResultUpdate};
 use crate::utils::helper::get_document;
+use crate::utils::shutdown;
+use grammers_client::types::{Chat, Message};
 use grammers_client::{Client, Update};
 use url::Url;
 
+const NO_ACCESS_REPLY: &str = "Sorry, you don't have access to this bot";
+
 pub async fn handle_update(bot: Client, update: Update) -> ResultUpdate {
+    let config = AppConfig::current();
+    let access_control = AccessControl::from_config(&config);
+
     // Handle only messages sent by users
     let message = match update {
         Update::NewMessage(message) => {
@@ -15,29 +27,75 @@ pub async fn handle_update(bot: Client, update: Update) -> ResultUpdate {
             }
             message
         }
-        Update::CallbackQuery(message) => {
-            handle_query(message).await?;
+        Update::CallbackQuery(query) => {
+            match access_control.access_of(query.sender().id()) {
+                Access::Allowed(role) if role >= Role::Downloader => {
+                    handle_query(bot, query, role).await?
+                }
+                Access::Allowed(_) => {
+                    query
+                        .answer()
+                        .text("You don't have permission for this")
+                        .send()
+                        .await?
+                }
+                _ => query.answer().text(NO_ACCESS_REPLY).send().await?,
+            }
             return Ok(());
         }
         _ => return Ok(()),
     };
     let chat = message.chat();
 
+    // Authorize sender before any handler runs
+    let sender = match message.sender() {
+        Some(sender) => sender,
+        None => return Ok(()),
+    };
+    let role = match access_control.access_of(sender.id()) {
+        Access::Allowed(role) => role,
+        access => {
+            log::warn!("User without access {} ({})", sender.name(), sender.id());
+            refuse_user(bot, &message, &sender, access, &config).await?;
+            return Ok(());
+        }
+    };
+
+    // No new jobs once shutdown started, running ones get the grace period
+    if shutdown::is_shutting_down() {
+        message
+            .reply("The bot is restarting, please send this again in a minute")
+            .await?;
+        return Ok(());
+    }
+
     // Handle Document if available
     if get_document(message.clone()).is_some() {
-        handle_document(bot, message).await?;
+        if has_role(&message, role, Role::Downloader).await? {
+            handle_document(bot, message).await?;
+        }
         return Ok(());
     }
 
     // Check if a message start with /, to handle as command
     if message.text().starts_with("/") {
-        handle_command(bot, chat, message).await?;
-        return Ok(());
+        let entry = AuditEntry::from_message(&message, "command").file_name(message.text());
+        if !has_role(&message, role, required_role(message.text())).await? {
+            audit(&config, entry.result("refused"));
+            return Ok(());
+        }
+        let result = handle_command(bot, chat, message)
+            .await
+            .map_err(|e| e.to_string());
+        audit(&config, entry.outcome(&result, false));
+        return Ok(result?);
     }
 
     // Check if text is a url
     if Url::parse(message.text()).is_ok() {
-        handle_url(bot, message).await?;
+        if has_role(&message, role, Role::Downloader).await? {
+            handle_url(message).await?;
+        }
         return Ok(());
     }
 
@@ -47,3 +105,41 @@ pub async fn handle_update(bot: Client, update: Update) -> ResultUpdate {
         .await?;
     Ok(())
 }
+
+/// Reply with a refusal if `role` is lower than `required`
+async fn has_role(message: &Message, role: Role, required: Role) -> ResultGram<bool> {
+    if role >= required {
+        return Ok(true);
+    }
+    log::warn!("Refused {} for {} user", message.text(), role.name());
+    message
+        .reply(format!(
+            "Sorry, this needs {} access, you have {} access",
+            required.name(),
+            role.name()
+        ))
+        .await?;
+    Ok(false)
+}
+
+/// Answer users without access, unknown users are sent to admin for approval
+async fn refuse_user(
+    bot: Client,
+    message: &Message,
+    user: &Chat,
+    access: Access,
+    config: &AppConfig,
+) -> ResultGram<()> {
+    let reply = match access {
+        Access::Unknown => {
+            request_access(bot, user, config).await?;
+            "This bot is private, your access request was sent to the admin"
+        }
+        Access::Pending => "Your access request is still waiting for the admin",
+        Access::Denied => NO_ACCESS_REPLY,
+        // Blocked users get no answer at all
+        Access::Blocked | Access::Allowed(_) => return Ok(()),
+    };
+    message.reply(reply).await?;
+    Ok(())
+}
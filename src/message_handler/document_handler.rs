@@ -1,90 +1,150 @@
+use crate::app_config::AppConfig;
+use crate::message_handler::aria2_handler::{download_with_aria2, Aria2Job};
+use crate::utils::aria2::Aria2Client;
+use crate::utils::audit::{audit, AuditEntry};
+use crate::utils::bot_error::BotError;
 use crate::utils::custom_result::ResultGram;
-use crate::utils::download_utils::{delete_file, download_media_concurrent};
+use crate::utils::download_registry::Download;
+use crate::utils::download_utils::{
+    delete_file, download_media_concurrent, download_to_memory, path_in, plain_file_name,
+};
 use crate::utils::helper::{ask_query, get_custom_file_name, get_directory, get_document};
+use crate::utils::hooks::download_finished;
+use crate::utils::pending::{self, PendingJob, PendingKind};
+use crate::utils::quota::{admit, remaining_allowance, ActiveDownload};
 use grammers_client::types::{media, Message};
 use grammers_client::Client;
-use std::collections::HashMap;
 use std::fs::create_dir_all;
-use std::sync::{Arc, Mutex};
-use tokio_util::sync::CancellationToken;
+use std::path::Path;
+use std::sync::atomic::Ordering;
 
-lazy_static::lazy_static! {
-    static ref CANCEL_DOWNLOAD: Arc<Mutex<HashMap<u8, CancellationToken>>> = Arc::new(Mutex::new(HashMap::new()));
-    static ref DOWNLOAD_ID_COUNTER: Arc<Mutex<u8>> = Arc::new(Mutex::new(0));
-}
-pub const DOWNLOAD_ID_QUERY: u8 = 192;
+const MAX_TORRENT_SIZE: i64 = 10 * 1024 * 1024;
 
 /// Handle Download Requests from bot
 pub async fn handle_document(bot: Client, message: Message) -> ResultGram<()> {
     let document = get_document(message.clone()).unwrap();
 
-    let directory_result: Option<String> = get_directory(bot.clone(), message.clone()).await?;
+    let config = AppConfig::current();
+
+    // Torrent files are handed to aria2 when it's configured
+    if is_torrent(&document) && document.size() <= MAX_TORRENT_SIZE {
+        if let Some(aria2) = Aria2Client::from_config(&config) {
+            let torrent = download_to_memory(bot.clone(), message.clone()).await?;
+            return download_with_aria2(message, aria2, Aria2Job::Torrent(torrent)).await;
+        }
+    }
+
+    // Refuse before asking anything if the file is over the user's limits
+    let size = document.size().max(0) as u64;
+    let quota = match admit(&message, document.name(), Some(size)).await? {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+
+    let directory_result: Option<String> = get_directory(message.clone()).await?;
     if directory_result.is_none() {
         return Ok(());
     }
 
     let use_default_file_name =
-        should_download_with_default_filename(bot.clone(), message.clone(), document.clone())
-            .await?;
+        should_download_with_default_filename(message.clone(), document.clone()).await?;
 
     log::info!("DefaulFileName: {use_default_file_name}");
 
-    let mut media_name: String = document.name().to_string();
+    // Names from Telegram can contain separators as well
+    let mut media_name: String =
+        plain_file_name(document.name()).unwrap_or_else(|| format!("document_{}", message.id()));
     if !use_default_file_name {
-        media_name = match get_custom_file_name(bot.clone(), message.clone()).await? {
+        media_name = match get_custom_file_name(message.clone()).await? {
             Some(name) => name,
             None => media_name,
         };
     }
 
     let directory = directory_result.unwrap();
-    let dest: String = format!("{}/{}", directory, media_name);
+    let dest: String = match path_in(&directory, &media_name) {
+        Some(dest) => dest,
+        None => {
+            message.reply("Invalid file name").await?;
+            return Ok(());
+        }
+    };
     log::debug!("Download to : {}", dest);
 
     // Create download directory if it doesn't exist
     if let Err(e) = create_dir_all(&directory) {
-        let error_message = format!("Failed to create download directory: {}", e.to_string());
-        message.reply(error_message).await?;
-        return Err(e.into());
+        let error = BotError::from_io(e, &directory);
+        log::error!("Failed to create download directory: {error}");
+        error.reply_to(&message, config.language).await?;
+        return Ok(());
     }
 
-    let download_id = {
-        let mut counter = DOWNLOAD_ID_COUNTER.lock().unwrap();
-        if *counter == 255 {
-            *counter = 0;
-        }
-        *counter = counter.wrapping_add(1);
-        *counter
-    };
-
-    let button_id: &[u8] = &[DOWNLOAD_ID_QUERY, download_id];
-    log::debug!("Downloading: {:?}", button_id);
+    download_document(bot, message, dest, media_name, 0, quota).await
+}
 
-    let cancel_token = CancellationToken::new();
-    {
-        let mut cancel_map = CANCEL_DOWNLOAD.lock().unwrap();
-        cancel_map.insert(download_id, cancel_token.clone());
-    }
+/// Download the document of `message` to `dest` and reply with the result
+/// `resume_from` continues a download interrupted by shutdown, 0 starts over
+pub async fn download_document(
+    bot: Client,
+    message: Message,
+    dest: String,
+    media_name: String,
+    resume_from: u64,
+    quota: ActiveDownload,
+) -> ResultGram<()> {
+    let config = AppConfig::current();
+    let user_id = message.sender().map(|sender| sender.id()).unwrap_or(0);
+    let size = get_document(message.clone())
+        .map(|document| document.size().max(0) as u64)
+        .unwrap_or(0);
+
+    let download = Download::register(&message, &media_name, quota);
+    download.progress().resume_at(resume_from);
+    log::debug!("Downloading: {}", download.id());
+
+    let entry = AuditEntry::from_message(&message, "download")
+        .file_name(&media_name)
+        .size(size)
+        .destination(&dest);
+    audit(&config, entry.clone().result("started"));
 
-    let mut error: Option<String> = None;
     let start_time = std::time::Instant::now();
 
-    if let Err(e) = download_media_concurrent(
+    let result = download_media_concurrent(
         bot.clone(),
         dest.clone(),
         4,
         message.clone(),
-        button_id,
-        cancel_token.clone(),
+        &download,
+        resume_from,
     )
     .await
-    {
-        error = Some(format!("Failed To Download: {}", e.to_string()));
-        log::error!("Failed {}", error.clone().unwrap());
+    .map_err(|e| download.error(e));
+
+    // Keep the file and continue from the checkpoint after restart
+    if let Err(BotError::Interrupted) = result {
+        let offset = download.checkpoint().load(Ordering::SeqCst);
+        drop(download);
+        let kind = PendingKind::Document { offset };
+        if let Err(e) = pending::add(&config, PendingJob::new(&message, &media_name, &dest, kind)) {
+            log::error!("Failed to save interrupted download {media_name}: {e}");
+        }
+        audit(
+            &config,
+            entry.duration(start_time.elapsed()).result("interrupted"),
+        );
+        return Ok(());
     }
 
-    if error.is_some() {
-        message.reply(error.unwrap()).await?;
+    let entry = entry
+        .duration(start_time.elapsed())
+        .outcome(&result, download.is_cancelled_by_user());
+    drop(download);
+    audit(&config, entry);
+
+    if let Err(error) = result {
+        log::error!("Failed To Download: {error}");
+        error.reply_to(&message, config.language).await?;
         delete_file(dest.clone()).await;
     } else {
         let download_complete_time = start_time.elapsed().as_secs();
@@ -95,45 +155,31 @@ pub async fn handle_document(bot: Client, message: Message) -> ResultGram<()> {
         if download_complete_time > 3600 {
             download_time = format!("{:.1} hr", download_complete_time / 3600);
         }
-        message
-            .reply(format!(
-                "Download Completed in {} \nStored at: {}",
-                download_time, dest
-            ))
-            .await?;
-    }
-
-    // Remove from map
-    {
-        let mut cancel_map = CANCEL_DOWNLOAD.lock().unwrap();
-        cancel_map.remove(&button_id[0]);
+        download_finished(&config, &message, Path::new(&dest), start_time.elapsed()).await;
+        let mut reply = format!(
+            "Download Completed in {} \nStored at: {}",
+            download_time, dest
+        );
+        if let Some(allowance) = remaining_allowance(&config, user_id) {
+            reply = format!("{reply}\n{allowance}");
+        }
+        message.reply(reply).await?;
     }
 
     Ok(())
 }
 
-/// Handle Cancel Requests
-pub async fn cancel_download(id: &[u8]) -> String {
-    if id.len() < 2 {
-        return "Invalid Message Id".to_string();
-    }
-    let download_id = id[1];
-    log::info!("Cancel Download: {}", download_id);
-
-    if let Some(cancel_token) = CANCEL_DOWNLOAD.lock().unwrap().get(&download_id) {
-        cancel_token.cancel();
-    }
-    return "Download will be canceled shortly".to_string();
+fn is_torrent(document: &media::Document) -> bool {
+    document.mime_type() == Some("application/x-bittorrent")
+        || document.name().to_lowercase().ends_with(".torrent")
 }
 
 pub async fn should_download_with_default_filename(
-    bot: Client,
     message: Message,
     document: media::Document,
 ) -> ResultGram<bool> {
     let options: Vec<String> = vec!["Yes".to_string(), "No".to_string()];
     let choosed_option = match ask_query(
-        bot.clone(),
         message,
         format!("Download with default filename: \n{}", document.name()).as_str(),
         options.clone(),
@@ -0,0 +1,240 @@
+use grammers_client::types::{CallbackQuery, Chat, Message};
+use grammers_client::{button, reply_markup, Client, InputMessage};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::app_config::AppConfig;
+use crate::utils::access_control::{Access, AccessControl, Role};
+use crate::utils::audit::{audit, AuditEntry};
+use crate::utils::callback_data::{CallbackData, HistoryAction};
+use crate::utils::custom_result::ResultGram;
+use crate::utils::history::{self, HistoryEntry};
+
+const PAGE_SIZE: usize = 5;
+const FIND_LIMIT: usize = 10;
+
+/// Admin sees downloads of everyone, others only their own
+fn visible_user(user_id: i64, role: Role) -> Option<i64> {
+    match role {
+        Role::Admin => None,
+        _ => Some(user_id),
+    }
+}
+
+fn role_of(config: &AppConfig, user_id: i64) -> Role {
+    match AccessControl::from_config(config).access_of(user_id) {
+        Access::Allowed(role) => role,
+        _ => Role::ReadOnly,
+    }
+}
+
+fn entry_button(text: &str, action: HistoryAction, entry: &HistoryEntry) -> button::Inline {
+    let data = CallbackData::HistoryEntry {
+        action,
+        entry_id: entry.id,
+    };
+    button::inline(text, data.encode())
+}
+
+/// Numbered entries with Send, Delete and Details buttons, `extra` is added as last row
+fn entries_message(
+    title: String,
+    entries: &[HistoryEntry],
+    first_number: usize,
+    extra: Vec<button::Inline>,
+) -> InputMessage {
+    let mut lines = vec![title];
+    let mut buttons: Vec<Vec<button::Inline>> = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let number = first_number + index + 1;
+        lines.push(format!("{number}. {}", entry.summary()));
+        let mut row = Vec::new();
+        if !entry.deleted {
+            row.push(entry_button(
+                &format!("Send {number}"),
+                HistoryAction::Send,
+                entry,
+            ));
+            row.push(entry_button(
+                &format!("Delete {number}"),
+                HistoryAction::Delete,
+                entry,
+            ));
+        }
+        row.push(entry_button(
+            &format!("Details {number}"),
+            HistoryAction::Details,
+            entry,
+        ));
+        buttons.push(row);
+    }
+    if !extra.is_empty() {
+        buttons.push(extra);
+    }
+    InputMessage::text(lines.join("\n")).reply_markup(&reply_markup::inline(buttons))
+}
+
+/// One page of `/recent`, newest first
+fn recent_page(config: &AppConfig, user_id: i64, role: Role, page: u32) -> InputMessage {
+    let visible = visible_user(user_id, role);
+    let (mut entries, total) = history::recent(config, visible, page as usize, PAGE_SIZE);
+    if total == 0 {
+        return InputMessage::text("No downloads yet");
+    }
+    // Buttons sent before HISTORY_FILE changed can point past the end
+    let pages = total.div_ceil(PAGE_SIZE);
+    let page = (page as usize).min(pages - 1);
+    if entries.is_empty() {
+        entries = history::recent(config, visible, page, PAGE_SIZE).0;
+    }
+
+    let mut navigation = Vec::new();
+    if page > 0 {
+        let data = CallbackData::HistoryPage {
+            page: page as u32 - 1,
+        };
+        navigation.push(button::inline("Newer", data.encode()));
+    }
+    if page + 1 < pages {
+        let data = CallbackData::HistoryPage {
+            page: page as u32 + 1,
+        };
+        navigation.push(button::inline("Older", data.encode()));
+    }
+    let title = format!("Downloads, page {} of {pages}:", page + 1);
+    entries_message(title, &entries, page * PAGE_SIZE, navigation)
+}
+
+/// `/recent`, latest downloads in pages
+pub async fn handle_recent(message: Message, config: &AppConfig) -> ResultGram<()> {
+    let user_id = message.sender().map(|sender| sender.id()).unwrap_or(0);
+    let reply = recent_page(config, user_id, role_of(config, user_id), 0);
+    message.reply(reply).await?;
+    Ok(())
+}
+
+/// Downloads with `text` in the name
+pub async fn handle_find(message: Message, config: &AppConfig, text: &str) -> ResultGram<()> {
+    let user_id = message.sender().map(|sender| sender.id()).unwrap_or(0);
+    let visible = visible_user(user_id, role_of(config, user_id));
+    let entries = history::find(config, visible, text, FIND_LIMIT);
+    if entries.is_empty() {
+        message.reply(format!("No downloads match {text}")).await?;
+        return Ok(());
+    }
+    let title = format!("Downloads matching {text}:");
+    message
+        .reply(entries_message(title, &entries, 0, Vec::new()))
+        .await?;
+    Ok(())
+}
+
+/// Newer and Older buttons of `/recent` replace the page
+pub async fn show_page(query: CallbackQuery, role: Role, page: u32) -> ResultGram<()> {
+    let config = AppConfig::current();
+    let reply = recent_page(&config, query.sender().id(), role, page);
+    query.answer().edit(reply).await?;
+    Ok(())
+}
+
+/// Send, Delete and Details buttons, only for who downloaded the file or admin
+pub async fn handle_history_action(
+    bot: Client,
+    query: CallbackQuery,
+    role: Role,
+    action: HistoryAction,
+    entry_id: u64,
+) -> ResultGram<()> {
+    let config = AppConfig::current();
+    let user_id = query.sender().id();
+    let entry = match history::get(&config, entry_id) {
+        Some(entry) if entry.user_id == user_id || role == Role::Admin => entry,
+        Some(_) => {
+            query
+                .answer()
+                .text("Only who downloaded the file or admin can do this")
+                .send()
+                .await?;
+            return Ok(());
+        }
+        None => {
+            query.answer().text("Download not found").send().await?;
+            return Ok(());
+        }
+    };
+
+    match action {
+        HistoryAction::Details => {
+            query.answer().send().await?;
+            bot.send_message(query.chat(), entry.details()).await?;
+        }
+        HistoryAction::Send => {
+            if entry.deleted || !Path::new(&entry.path).exists() {
+                query
+                    .answer()
+                    .text("File is no longer on disk")
+                    .send()
+                    .await?;
+                return Ok(());
+            }
+            query
+                .answer()
+                .text(format!("Sending {}", entry.name))
+                .send()
+                .await?;
+
+            let start_time = Instant::now();
+            let result = send_file(&bot, query.chat(), &entry)
+                .await
+                .map_err(|e| e.to_string());
+            let audit_entry = AuditEntry::new(user_id, query.chat().id(), "history_send")
+                .file_name(&entry.path)
+                .size(entry.size)
+                .duration(start_time.elapsed())
+                .outcome(&result, false);
+            audit(&config, audit_entry);
+            if let Err(error) = result {
+                log::error!("Failed to send {}: {error}", entry.path);
+                bot.send_message(
+                    query.chat(),
+                    format!("Failed to send {}: {error}", entry.name),
+                )
+                .await?;
+            }
+        }
+        HistoryAction::Delete => {
+            let result = delete_entry_file(&config, &entry).await;
+            let audit_entry = AuditEntry::new(user_id, query.chat().id(), "history_delete")
+                .file_name(&entry.path)
+                .size(entry.size)
+                .outcome(&result, false);
+            audit(&config, audit_entry);
+            let response = match result {
+                Ok(()) => format!("Deleted {}", entry.name),
+                Err(error) => format!("Failed to delete {}: {error}", entry.name),
+            };
+            query.answer().text(response).send().await?;
+        }
+    }
+    Ok(())
+}
+
+async fn send_file(bot: &Client, chat: &Chat, entry: &HistoryEntry) -> ResultGram<()> {
+    let uploaded = bot.upload_file(&entry.path).await?;
+    bot.send_message(chat, InputMessage::text(&entry.name).document(uploaded))
+        .await?;
+    Ok(())
+}
+
+/// A file removed by hand is only marked as deleted
+async fn delete_entry_file(config: &AppConfig, entry: &HistoryEntry) -> Result<(), String> {
+    if !entry.deleted {
+        match tokio::fs::remove_file(&entry.path).await {
+            Ok(()) => log::info!("Deleted {}", entry.path),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    history::mark_deleted(config, entry.id).map_err(|e| e.to_string())
+}
@@ -1,5 +1,9 @@
+pub mod aria2_handler;
 pub mod command_handler;
 pub mod default_handler;
 pub mod document_handler;
+pub mod history_handler;
 pub mod query_handler;
+pub mod resume_handler;
 pub mod url_handler;
+pub mod user_handler;
@@ -1,27 +1,87 @@
 use grammers_client::types::CallbackQuery;
+use grammers_client::Client;
 
 use crate::{
-    message_handler::document_handler::{cancel_download, DOWNLOAD_ID_QUERY},
+    app_config::AppConfig,
+    message_handler::history_handler::{handle_history_action, show_page},
+    message_handler::user_handler::decide_access,
+    utils::access_control::Role,
+    utils::audit::{audit, AuditEntry},
+    utils::callback_data::{CallbackData, CallbackError},
     utils::custom_result::ResultGram,
+    utils::download_registry,
 };
 
-pub async fn handle_query(query: CallbackQuery) -> ResultGram<()> {
-    println!("Got CallbackQuery Query {:?}", query.data());
-    let mut response = "Invalid Button".to_string();
+const EXPIRED_BUTTON: &str = "This button has expired, please try again";
 
-    if query.data().len() == 0 {
-        query.answer().text(response).send().await?;
-        return Ok(());
-    }
+/// Route button presses by their callback data
+pub async fn handle_query(bot: Client, query: CallbackQuery, role: Role) -> ResultGram<()> {
+    log::debug!("Got CallbackQuery Query {:?}", query.data());
 
-    // First byte is query type
-    let query_type = query.data()[0];
+    let data = match CallbackData::decode(query.data()) {
+        Ok(data) => data,
+        Err(error) => {
+            if error == CallbackError::Invalid {
+                log::warn!("Invalid callback data {:?}", query.data());
+            }
+            query.answer().text(EXPIRED_BUTTON).send().await?;
+            return Ok(());
+        }
+    };
 
-    // Handle Query Type
-    if query_type == DOWNLOAD_ID_QUERY {
-        response = cancel_download(query.data()).await;
-    }
+    let response = match data {
+        CallbackData::CancelDownload { download_id } => {
+            let response = cancel_download(download_id, query.sender().id(), role);
+            let config = AppConfig::current();
+            let entry = AuditEntry::new(query.sender().id(), query.chat().id(), "cancel_download");
+            audit(&config, entry.result(&response));
+            response
+        }
+
+        // Access decisions replace the prompt with the outcome
+        CallbackData::UserAccess { action, user_id } => {
+            if role != Role::Admin {
+                query.answer().text("Only admin can do this").send().await?;
+                return Ok(());
+            }
+            let config = AppConfig::current();
+            let outcome = decide_access(bot, action, user_id, &config).await?;
+            let entry = AuditEntry::new(query.sender().id(), query.chat().id(), "user_access");
+            audit(&config, entry.result(&outcome));
+            query.answer().edit(outcome).await?;
+            return Ok(());
+        }
+
+        CallbackData::HistoryPage { page } => {
+            show_page(query, role, page).await?;
+            return Ok(());
+        }
+
+        CallbackData::HistoryEntry { action, entry_id } => {
+            handle_history_action(bot, query, role, action, entry_id).await?;
+            return Ok(());
+        }
+
+        // Prompt buttons reach here only if nobody waits for them anymore
+        CallbackData::Prompt { .. } => "This button has expired or is not for you".to_string(),
+    };
 
     query.answer().text(response).send().await?;
-    return Ok(());
+    Ok(())
+}
+
+/// Only the user who started the download or admin can cancel it
+fn cancel_download(download_id: u64, user_id: i64, role: Role) -> String {
+    let download = match download_registry::get(download_id) {
+        Some(download) => download,
+        None => return "Download already finished".to_string(),
+    };
+    if download.owner_id != user_id && role != Role::Admin {
+        return "Only who started the download or admin can cancel it".to_string();
+    }
+    log::info!("Cancel Download: {download_id}");
+    match download_registry::cancel(download_id) {
+        Some(_) => "Download will be canceled shortly".to_string(),
+        None => "Download already finished".to_string(),
+    }
 }
@@ -0,0 +1,116 @@
+use grammers_client::Client;
+use std::path::PathBuf;
+use url::Url;
+
+use crate::app_config::AppConfig;
+use crate::message_handler::aria2_handler::follow_aria2;
+use crate::message_handler::command_handler::download_drive_to;
+use crate::message_handler::document_handler::download_document;
+use crate::message_handler::url_handler::{download_to, download_video_to};
+use crate::utils::aria2::Aria2Client;
+use crate::utils::custom_result::ResultGram;
+use crate::utils::download_utils::delete_file;
+use crate::utils::gdrive::parse_drive_link;
+use crate::utils::pending::{self, PendingJob, PendingKind};
+use crate::utils::quota::admit;
+
+/// Continue the downloads interrupted by the last shutdown
+pub fn resume_pending(bot: Client, config: &AppConfig) {
+    let jobs = pending::take_all(config);
+    if !jobs.is_empty() {
+        log::info!("Resuming {} interrupted downloads", jobs.len());
+    }
+    for job in jobs {
+        let bot = bot.clone();
+        tokio::spawn(async move {
+            let name = job.name.clone();
+            if let Err(e) = resume(bot, job).await {
+                log::error!("Failed to resume {name}: {e}");
+            }
+        });
+    }
+}
+
+async fn resume(bot: Client, job: PendingJob) -> ResultGram<()> {
+    let chat = job.chat().ok_or("Invalid chat in pending download")?;
+    let message = bot
+        .get_messages_by_id(chat, &[job.message_id])
+        .await?
+        .pop()
+        .flatten();
+    let message = match message {
+        Some(message) => message,
+        None => {
+            log::warn!("Message of {} was deleted, not resuming", job.name);
+            discard(&AppConfig::current(), job).await;
+            return Ok(());
+        }
+    };
+
+    let quota = admit(&message, &job.name, None).await?;
+    let quota = match quota {
+        Some(quota) => quota,
+        None => {
+            discard(&AppConfig::current(), job).await;
+            return Ok(());
+        }
+    };
+
+    match job.kind {
+        PendingKind::Document { offset } => {
+            log::info!("Resuming {} from {offset} bytes", job.name);
+            download_document(bot, message, job.path, job.name, offset, quota).await
+        }
+        PendingKind::Url { url } => {
+            log::info!("Restarting {} from {url}", job.name);
+            download_to(message, Url::parse(&url)?, job.path, job.name, quota).await
+        }
+        PendingKind::Aria2 { gid, completed } => {
+            let config = AppConfig::current();
+            let aria2 =
+                Aria2Client::from_config(&config).ok_or("aria2 is not configured anymore")?;
+            log::info!("Following aria2 job {gid} of {} again", job.name);
+            let directory = PathBuf::from(job.path);
+            follow_aria2(message, aria2, gid, job.name, directory, quota, completed).await
+        }
+        PendingKind::Drive { link } => {
+            log::info!("Restarting Drive download {link}");
+            let drive_link =
+                parse_drive_link(&link).ok_or("Invalid Drive link in pending download")?;
+            download_drive_to(message, link, drive_link, PathBuf::from(job.path), quota).await
+        }
+        PendingKind::Video { url, quality } => {
+            log::info!("Restarting {} from {url}", job.name);
+            let config = AppConfig::current();
+            let directory = PathBuf::from(job.path);
+            download_video_to(
+                message,
+                Url::parse(&url)?,
+                job.name,
+                quality,
+                directory,
+                config,
+                quota,
+            )
+            .await
+        }
+    }
+}
+
+/// Clean up after a job that won't be resumed
+async fn discard(config: &AppConfig, job: PendingJob) {
+    match job.kind {
+        PendingKind::Document { .. } | PendingKind::Url { .. } => delete_file(job.path).await,
+        PendingKind::Aria2 { gid, .. } => {
+            let aria2 = match Aria2Client::from_config(config) {
+                Some(aria2) => aria2,
+                None => return,
+            };
+            if let Err(e) = aria2.remove(&gid).await {
+                log::warn!("Failed to remove aria2 job {gid}: {e}");
+            }
+        }
+        // Whatever was written is in a directory shared with other files
+        PendingKind::Drive { .. } | PendingKind::Video { .. } => {}
+    }
+}
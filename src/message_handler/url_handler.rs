@@ -1,9 +1,345 @@
-use grammers_client::{types::Message, Client};
+use grammers_client::types::Message;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::{fs, io::AsyncWriteExt};
+use url::Url;
 
+use crate::app_config::AppConfig;
+use crate::message_handler::aria2_handler::{download_with_aria2, Aria2Job};
+use crate::message_handler::command_handler::download_gdrive;
+use crate::utils::aria2::Aria2Client;
+use crate::utils::audit::{audit, AuditEntry};
+use crate::utils::bot_error::BotError;
 use crate::utils::custom_result::ResultGram;
+use crate::utils::download_registry::Download;
+use crate::utils::download_utils::{delete_file, path_in, plain_file_name};
+use crate::utils::external_tool::is_installed;
+use crate::utils::gdrive::parse_drive_link;
+use crate::utils::helper::{ask_query, get_custom_file_name, get_directory};
+use crate::utils::hooks::download_finished;
+use crate::utils::pending::{self, PendingJob, PendingKind};
+use crate::utils::progress::ProgressReporter;
+use crate::utils::quota::{admit, ActiveDownload};
+use crate::utils::url_policy::UrlPolicy;
+use crate::utils::ytdlp::{self, VideoInfo, VideoQuality, YTDLP_PROGRAM};
 
-pub async fn handle_url(_: Client, message: Message) -> ResultGram<()> {
-    // Check if its a gdrive url
-    message.reply("Url downloading not supported yet").await?;
-    return Ok(());
+pub async fn handle_url(message: Message) -> ResultGram<()> {
+    let url = Url::parse(message.text().trim())?;
+
+    // Google Drive share links are handled by the Drive client
+    if parse_drive_link(url.as_str()).is_some() {
+        return download_gdrive(message).await;
+    }
+    let config = AppConfig::current();
+    let policy = UrlPolicy::from_config(&config);
+
+    if url.scheme() == "magnet" {
+        return match Aria2Client::from_config(&config) {
+            Some(aria2) => {
+                download_with_aria2(message, aria2, Aria2Job::Magnet(url.to_string())).await
+            }
+            None => {
+                message
+                    .reply("Magnet links need aria2, set ARIA2_RPC_URL")
+                    .await?;
+                Ok(())
+            }
+        };
+    }
+
+    // Check the link before asking anything from user
+    let blocked_reason = policy.resolve(&url).await.err().map(|e| e.to_string());
+    if let Some(reason) = blocked_reason {
+        log::warn!("Blocked url {url}: {reason}");
+        message
+            .reply(format!("Can't download this link: {reason}"))
+            .await?;
+        return Ok(());
+    }
+    let quota = match admit(&message, url.as_str(), None).await? {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+
+    // Video sites are handed to yt-dlp when it's installed
+    if ytdlp::is_video_site(&url, &config.video_domains) && is_installed(YTDLP_PROGRAM) {
+        if let Some(info) = ytdlp::probe(url.as_str()).await {
+            return download_video(message, url, info, config, quota).await;
+        }
+    }
+
+    let directory = match get_directory(message.clone()).await? {
+        Some(directory) => directory,
+        None => return Ok(()),
+    };
+
+    let media_name = match file_name_from_url(&url) {
+        Some(name) => name,
+        None => match get_custom_file_name(message.clone()).await? {
+            Some(name) => name,
+            None => return Ok(()),
+        },
+    };
+
+    if let Err(e) = create_dir_all(&directory) {
+        let error = BotError::from_io(e, &directory);
+        log::error!("Failed to create download directory: {error}");
+        error
+            .reply_to(&message, AppConfig::current().language)
+            .await?;
+        return Ok(());
+    }
+    let dest = match path_in(&directory, &media_name) {
+        Some(dest) => dest,
+        None => {
+            message.reply("Invalid file name").await?;
+            return Ok(());
+        }
+    };
+    download_to(message, url, dest, media_name, quota).await
+}
+
+/// Download `url` to `dest` and reply with the result
+/// Also used to start a download interrupted by shutdown again
+pub async fn download_to(
+    message: Message,
+    url: Url,
+    dest: String,
+    media_name: String,
+    quota: ActiveDownload,
+) -> ResultGram<()> {
+    let config = AppConfig::current();
+    let policy = UrlPolicy::from_config(&config);
+    log::info!("Url Download: {url} to {dest}");
+    let entry = AuditEntry::from_message(&message, "download")
+        .file_name(&media_name)
+        .destination(&dest);
+    audit(&config, entry.clone().result("started"));
+
+    let mut download = Download::register(&message, &media_name, quota);
+    let start_time = Instant::now();
+    let result = download_url(&policy, url.clone(), dest.clone(), &message, &download)
+        .await
+        .map_err(|e| download.error(e));
+
+    // Servers can't be trusted to support ranges, the link is downloaded again after restart
+    if let Err(BotError::Interrupted) = result {
+        download.discard_usage();
+        drop(download);
+        let kind = PendingKind::Url {
+            url: url.to_string(),
+        };
+        if let Err(e) = pending::add(&config, PendingJob::new(&message, &media_name, &dest, kind)) {
+            log::error!("Failed to save interrupted download {media_name}: {e}");
+        }
+        audit(
+            &config,
+            entry.duration(start_time.elapsed()).result("interrupted"),
+        );
+        delete_file(dest).await;
+        return Ok(());
+    }
+
+    let mut entry = entry
+        .duration(start_time.elapsed())
+        .outcome(&result, download.is_cancelled_by_user());
+    drop(download);
+    if let Ok(metadata) = std::fs::metadata(&dest) {
+        entry = entry.size(metadata.len());
+    }
+    audit(&config, entry);
+
+    if let Err(error) = result {
+        log::error!("Failed {error}");
+        error.reply_to(&message, config.language).await?;
+        delete_file(dest).await;
+        return Ok(());
+    }
+    download_finished(&config, &message, Path::new(&dest), start_time.elapsed()).await;
+
+    message
+        .reply(format!(
+            "Download Completed in {} sec \nStored at: {}",
+            start_time.elapsed().as_secs(),
+            dest
+        ))
+        .await?;
+    Ok(())
+}
+
+/// Ask for quality and directory, then download with yt-dlp
+async fn download_video(
+    message: Message,
+    url: Url,
+    info: VideoInfo,
+    config: AppConfig,
+    quota: ActiveDownload,
+) -> ResultGram<()> {
+    let qualities = info.quality_options();
+    let choosed_option = ask_query(
+        message.clone(),
+        format!("Choose quality ({}):\n{}", info.extractor, info.title).as_str(),
+        qualities.iter().map(|quality| quality.label()).collect(),
+    )
+    .await?;
+    let quality = match choosed_option.and_then(|index| qualities.get(index as usize)) {
+        Some(quality) => quality.clone(),
+        None => return Ok(()),
+    };
+
+    let directory = match get_directory(message.clone()).await? {
+        Some(directory) => PathBuf::from(directory),
+        None => return Ok(()),
+    };
+    if let Err(e) = create_dir_all(&directory) {
+        let error = BotError::from_io(e, &directory.to_string_lossy());
+        log::error!("Failed to create download directory: {error}");
+        error
+            .reply_to(&message, AppConfig::current().language)
+            .await?;
+        return Ok(());
+    }
+    download_video_to(message, url, info.title, quality, directory, config, quota).await
+}
+
+/// Download `url` with yt-dlp into `directory` and reply with the result
+/// Also used to start a download interrupted by shutdown again
+pub async fn download_video_to(
+    message: Message,
+    url: Url,
+    title: String,
+    quality: VideoQuality,
+    directory: PathBuf,
+    config: AppConfig,
+    quota: ActiveDownload,
+) -> ResultGram<()> {
+    log::info!("yt-dlp Download: {url} ({})", quality.label());
+    let entry = AuditEntry::from_message(&message, "download")
+        .file_name(&title)
+        .destination(&directory.to_string_lossy());
+    audit(&config, entry.clone().result("started"));
+
+    let mut download = Download::register(&message, &title, quota);
+    let cancel_token = download.cancel_token();
+    let progress = download.progress();
+    let on_progress = |name: &str, downloaded: u64, total: u64| {
+        progress.set(name, downloaded, total);
+    };
+
+    let start_time = Instant::now();
+    let timeout = Duration::from_secs(config.external_tool_timeout);
+    let mut reporter = ProgressReporter::for_chat(&message, &download, &config).await?;
+    let result = reporter
+        .run(ytdlp::download(
+            url.as_str(),
+            &quality,
+            &directory,
+            timeout,
+            cancel_token,
+            &on_progress,
+        ))
+        .await
+        .map_err(|e| download.error(e));
+
+    // yt-dlp picks up its partial files when started again after restart
+    if result.is_err() && download.is_interrupted() {
+        reporter.interrupt().await;
+        download.discard_usage();
+        drop(download);
+        let kind = PendingKind::Video {
+            url: url.to_string(),
+            quality,
+        };
+        let path = directory.to_string_lossy();
+        if let Err(e) = pending::add(&config, PendingJob::new(&message, &title, &path, kind)) {
+            log::error!("Failed to save interrupted download {title}: {e}");
+        }
+        audit(
+            &config,
+            entry.duration(start_time.elapsed()).result("interrupted"),
+        );
+        return Ok(());
+    }
+    reporter.finish(&result).await;
+
+    let mut entry = entry
+        .duration(start_time.elapsed())
+        .outcome(&result, download.is_cancelled_by_user());
+    if let Ok(path) = &result {
+        entry = entry.destination(&path.to_string_lossy());
+        if let Ok(metadata) = path.metadata() {
+            entry = entry.size(metadata.len());
+        }
+    }
+    audit(&config, entry);
+
+    match result {
+        Ok(path) => {
+            download_finished(&config, &message, &path, start_time.elapsed()).await;
+            message
+                .reply(format!(
+                    "Download Completed in {} sec \nStored at: {}",
+                    start_time.elapsed().as_secs(),
+                    path.display()
+                ))
+                .await?;
+        }
+        Err(error) => {
+            log::error!("yt-dlp download failed: {error}");
+            error.reply_to(&message, config.language).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Stream the response into file, every request goes through `UrlPolicy`
+async fn download_url(
+    policy: &UrlPolicy,
+    url: Url,
+    path: String,
+    message: &Message,
+    download: &Download,
+) -> ResultGram<()> {
+    let response = policy.get(url).await?.error_for_status()?;
+    let config = AppConfig::current();
+    let mut reporter = ProgressReporter::for_chat(message, download, &config).await?;
+    let result = reporter
+        .run(write_response(response, &path, download))
+        .await
+        .map_err(|e| download.error(e));
+    if result.is_err() && download.is_interrupted() {
+        reporter.interrupt().await;
+        return Err(BotError::Interrupted.into());
+    }
+    reporter.finish(&result).await;
+    Ok(result?)
+}
+
+/// Write the body into `path`, progress goes to the registered download
+pub async fn write_response(
+    mut response: reqwest::Response,
+    path: &str,
+    download: &Download,
+) -> ResultGram<()> {
+    let total_size = response.content_length().unwrap_or(0);
+    let (name, _, _) = download.progress().get();
+    let mut file = fs::File::create(path).await?;
+
+    let mut downloaded: u64 = 0;
+    while let Some(chunk) = response.chunk().await? {
+        if download.cancel_token().is_cancelled() {
+            return Err(BotError::Cancelled.into());
+        }
+        file.write_all(&chunk).await?;
+        downloaded += chunk.len() as u64;
+        download.progress().set(&name, downloaded, total_size);
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+/// Last path segment of the url, if it looks like a file name
+pub fn file_name_from_url(url: &Url) -> Option<String> {
+    plain_file_name(url.path_segments()?.next_back()?)
 }
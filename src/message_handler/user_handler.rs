@@ -0,0 +1,137 @@
+use grammers_client::types::{Chat, Message};
+use grammers_client::{button, reply_markup, Client, InputMessage};
+
+use crate::app_config::AppConfig;
+use crate::utils::access_control::AccessControl;
+use crate::utils::callback_data::{AccessAction, CallbackData};
+use crate::utils::custom_result::ResultGram;
+use crate::utils::helper::send_message_to_user;
+use crate::utils::user_store::{get_user, list_users, save_user, StoredUser, UserStatus};
+
+fn access_button(text: &str, action: AccessAction, user_id: i64) -> button::Inline {
+    button::inline(text, CallbackData::UserAccess { action, user_id }.encode())
+}
+
+fn display_name(user: &Chat) -> String {
+    match user.username() {
+        Some(username) => format!("{} @{} ({})", user.name(), username, user.id()),
+        None => format!("{} ({})", user.name(), user.id()),
+    }
+}
+
+fn status_name(status: UserStatus) -> &'static str {
+    match status {
+        UserStatus::Pending => "pending",
+        UserStatus::Approved => "approved",
+        UserStatus::Denied => "denied",
+        UserStatus::Blocked => "blocked",
+    }
+}
+
+/// Store unknown user as pending and ask admin to decide
+pub async fn request_access(bot: Client, user: &Chat, config: &AppConfig) -> ResultGram<()> {
+    let name = display_name(user);
+    let stored_user = StoredUser::new(user.id(), &name, user.pack().access_hash);
+    save_user(&config.users_file, stored_user)?;
+    log::info!("Access requested by {name}");
+
+    let prompt = InputMessage::text(format!("User {name} wants access")).reply_markup(
+        &reply_markup::inline(vec![vec![
+            access_button("Approve", AccessAction::Approve, user.id()),
+            access_button("Deny", AccessAction::Deny, user.id()),
+            access_button("Block", AccessAction::Block, user.id()),
+        ]]),
+    );
+    send_message_to_user(bot, config.user_id, prompt).await
+}
+
+/// Apply admin decision and tell the user, returns text that replaces the prompt
+pub async fn decide_access(
+    bot: Client,
+    action: AccessAction,
+    user_id: i64,
+    config: &AppConfig,
+) -> ResultGram<String> {
+    let mut user = match get_user(&config.users_file, user_id) {
+        Some(user) => user,
+        None => return Ok(format!("User {user_id} not found")),
+    };
+    let (status, admin_text, user_text) = match action {
+        AccessAction::Approve => (
+            UserStatus::Approved,
+            "Approved",
+            "Your access was approved, send /help to start",
+        ),
+        AccessAction::Deny => (
+            UserStatus::Denied,
+            "Denied",
+            "Sorry, your access request was denied",
+        ),
+        AccessAction::Block => (
+            UserStatus::Blocked,
+            "Blocked",
+            "You have been blocked from using this bot",
+        ),
+        AccessAction::Revoke => (
+            UserStatus::Denied,
+            "Revoked access of",
+            "Your access to this bot was revoked",
+        ),
+    };
+    user.status = status;
+    let name = user.name.clone();
+    save_user(&config.users_file, user)?;
+    log::info!("{admin_text} {name}");
+
+    let error = send_message_to_user(bot, user_id, user_text)
+        .await
+        .err()
+        .map(|e| e.to_string());
+    if let Some(error) = error {
+        log::warn!("Failed to tell {name} about the decision: {error}");
+    }
+    Ok(format!("{admin_text} {name}"))
+}
+
+/// List users with buttons to revoke or give access
+pub async fn handle_users_command(message: Message, config: &AppConfig) -> ResultGram<()> {
+    let access_control = AccessControl::from_config(config);
+    let mut lines: Vec<String> = vec!["Users from config:".to_string()];
+    for (id, role) in access_control.configured_users() {
+        lines.push(format!("{id}: {}", role.name()));
+    }
+
+    let stored_users = list_users(&config.users_file);
+    let mut buttons: Vec<Vec<button::Inline>> = Vec::new();
+    if !stored_users.is_empty() {
+        lines.push("\nUsers who asked for access:".to_string());
+    }
+    for user in stored_users {
+        lines.push(format!(
+            "{}: {} {}",
+            user.name,
+            status_name(user.status),
+            user.role.name()
+        ));
+        let button = match user.status {
+            UserStatus::Approved => access_button(
+                &format!("Revoke {}", user.name),
+                AccessAction::Revoke,
+                user.id,
+            ),
+            _ => access_button(
+                &format!("Approve {}", user.name),
+                AccessAction::Approve,
+                user.id,
+            ),
+        };
+        buttons.push(vec![button]);
+    }
+
+    let mut reply = InputMessage::text(lines.join("\n"));
+    if !buttons.is_empty() {
+        reply = reply.reply_markup(&reply_markup::inline(buttons));
+    }
+    message.reply(reply).await?;
+    Ok(())
+}
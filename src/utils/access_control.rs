@@ -0,0 +1,97 @@
+use super::user_store::{get_user, UserStatus};
+use crate::app_config::AppConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Roles are ordered, each role can do everything the lower ones can
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    ReadOnly,
+    Downloader,
+    Admin,
+}
+
+impl Role {
+    pub fn parse(value: &str) -> Option<Role> {
+        match value.trim().to_lowercase().as_str() {
+            "admin" => Some(Role::Admin),
+            "downloader" => Some(Role::Downloader),
+            "read-only" | "readonly" | "read_only" => Some(Role::ReadOnly),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Downloader => "downloader",
+            Role::ReadOnly => "read-only",
+        }
+    }
+}
+
+pub enum Access {
+    Allowed(Role),
+    Pending,
+    Denied,
+    Blocked,
+    Unknown,
+}
+
+/// Allowlist of users from `ALLOWED_USERS` and users approved by admin
+/// `USER_ID` is always admin
+pub struct AccessControl {
+    users: HashMap<i64, Role>,
+    users_file: String,
+}
+
+impl AccessControl {
+    pub fn from_config(config: &AppConfig) -> Self {
+        let mut users: HashMap<i64, Role> = HashMap::new();
+
+        // Entries look like `123456` or `123456:read-only`, default role is downloader
+        for entry in &config.allowed_users {
+            let (id, role) = match entry.split_once(':') {
+                Some((id, role)) => (id, Role::parse(role)),
+                None => (entry.as_str(), Some(Role::Downloader)),
+            };
+            match (id.trim().parse::<i64>(), role) {
+                (Ok(id), Some(role)) => {
+                    users.insert(id, role);
+                }
+                _ => log::error!("Invalid ALLOWED_USERS entry: {entry}"),
+            }
+        }
+        users.insert(config.user_id, Role::Admin);
+
+        AccessControl {
+            users,
+            users_file: config.users_file.clone(),
+        }
+    }
+
+    /// Configured users win over decisions stored by admin
+    pub fn access_of(&self, user_id: i64) -> Access {
+        if let Some(role) = self.users.get(&user_id) {
+            return Access::Allowed(*role);
+        }
+        match get_user(&self.users_file, user_id) {
+            Some(user) => match user.status {
+                UserStatus::Approved => Access::Allowed(user.role),
+                UserStatus::Pending => Access::Pending,
+                UserStatus::Denied => Access::Denied,
+                UserStatus::Blocked => Access::Blocked,
+            },
+            None => Access::Unknown,
+        }
+    }
+
+    /// Users from config, sorted by id
+    pub fn configured_users(&self) -> Vec<(i64, Role)> {
+        let mut users: Vec<(i64, Role)> =
+            self.users.iter().map(|(id, role)| (*id, *role)).collect();
+        users.sort();
+        users
+    }
+}
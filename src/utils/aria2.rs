@@ -0,0 +1,380 @@
+use super::bot_error::BotError;
+use super::custom_result::ResultGram;
+use crate::app_config::AppConfig;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const STATUS_KEYS: [&str; 8] = [
+    "gid",
+    "status",
+    "totalLength",
+    "completedLength",
+    "followedBy",
+    "errorMessage",
+    "files",
+    "bittorrent",
+];
+
+/// Client for an aria2c daemon started with `--enable-rpc`
+#[derive(Clone)]
+pub struct Aria2Client {
+    rpc_url: String,
+    secret: Option<String>,
+    http: reqwest::Client,
+}
+
+#[derive(Debug)]
+pub struct Aria2Status {
+    pub status: String,
+    pub name: String,
+    pub total: u64,
+    pub completed: u64,
+    pub followed_by: Vec<String>,
+    pub error_message: Option<String>,
+    pub files: Vec<String>,
+}
+
+impl Aria2Client {
+    pub fn new(rpc_url: &str, secret: Option<String>) -> Self {
+        Aria2Client {
+            rpc_url: rpc_url.to_string(),
+            secret,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// None if `ARIA2_RPC_URL` is not set
+    pub fn from_config(config: &AppConfig) -> Option<Self> {
+        let rpc_url = config.aria2_rpc_url.as_ref()?;
+        Some(Aria2Client::new(rpc_url, config.aria2_rpc_secret.clone()))
+    }
+
+    async fn call(&self, method: &str, params: Vec<Value>) -> ResultGram<Value> {
+        let mut all_params: Vec<Value> = Vec::new();
+        if let Some(secret) = &self.secret {
+            all_params.push(json!(format!("token:{secret}")));
+        }
+        all_params.extend(params);
+
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": "telegram_bot",
+            "method": method,
+            "params": all_params,
+        });
+        let response = self.http.post(&self.rpc_url).json(&request).send().await?;
+        let mut body: Value = response.json().await?;
+
+        if let Some(error) = body.get("error") {
+            let message = error["message"].as_str().unwrap_or("unknown error");
+            return Err(format!("aria2 {method} failed: {message}").into());
+        }
+        Ok(body["result"].take())
+    }
+
+    /// Add a magnet link, returns gid
+    pub async fn add_uri(&self, uri: &str, directory: &Path) -> ResultGram<String> {
+        let result = self
+            .call(
+                "aria2.addUri",
+                vec![json!([uri]), json!({ "dir": directory.to_string_lossy() })],
+            )
+            .await?;
+        Ok(result.as_str().ok_or("aria2 returned no gid")?.to_string())
+    }
+
+    /// Add content of a `.torrent` file, returns gid
+    pub async fn add_torrent(&self, torrent: &[u8], directory: &Path) -> ResultGram<String> {
+        let result = self
+            .call(
+                "aria2.addTorrent",
+                vec![
+                    json!(STANDARD.encode(torrent)),
+                    json!([]),
+                    json!({ "dir": directory.to_string_lossy() }),
+                ],
+            )
+            .await?;
+        Ok(result.as_str().ok_or("aria2 returned no gid")?.to_string())
+    }
+
+    /// Version of the aria2 daemon, also tells if the RPC url and secret work
+    pub async fn version(&self) -> ResultGram<String> {
+        let result = self.call("aria2.getVersion", vec![]).await?;
+        Ok(result["version"].as_str().unwrap_or("unknown").to_string())
+    }
+
+    pub async fn tell_status(&self, gid: &str) -> ResultGram<Aria2Status> {
+        let result = self
+            .call("aria2.tellStatus", vec![json!(gid), json!(STATUS_KEYS)])
+            .await?;
+        Ok(parse_status(&result))
+    }
+
+    pub async fn remove(&self, gid: &str) -> ResultGram<()> {
+        self.call("aria2.remove", vec![json!(gid)]).await?;
+        Ok(())
+    }
+
+    /// Poll until the job is done, magnet and torrent metadata jobs are followed to the real download
+    /// On cancel the job is removed from aria2, unless `keep_job` says it's followed again later
+    pub async fn wait<F, K>(
+        &self,
+        gid: &str,
+        cancel_token: &CancellationToken,
+        keep_job: &K,
+        on_progress: &F,
+    ) -> ResultGram<Aria2Status>
+    where
+        F: Fn(&str, u64, u64) + Sync,
+        K: Fn() -> bool + Sync,
+    {
+        let mut gid = gid.to_string();
+        loop {
+            if cancel_token.is_cancelled() && keep_job() {
+                log::info!("Leaving aria2 job {gid} running");
+                return Err(BotError::Interrupted.into());
+            }
+            if cancel_token.is_cancelled() {
+                let error = self.remove(&gid).await.err().map(|e| e.to_string());
+                if let Some(error) = error {
+                    log::warn!("Failed to remove aria2 job {gid}: {error}");
+                }
+                return Err(BotError::Cancelled.into());
+            }
+
+            let status = self.tell_status(&gid).await?;
+            match status.status.as_str() {
+                "complete" if !status.followed_by.is_empty() => {
+                    log::debug!("aria2 job {gid} followed by {:?}", status.followed_by);
+                    gid = status.followed_by[0].clone();
+                    continue;
+                }
+                "complete" => return Ok(status),
+                "error" => {
+                    let message = status.error_message.unwrap_or_default();
+                    return Err(BotError::ExternalTool {
+                        program: "aria2".to_string(),
+                        detail: message,
+                    }
+                    .into());
+                }
+                "removed" => return Err("Download removed from aria2".into()),
+                _ => on_progress(&status.name, status.completed, status.total),
+            }
+
+            tokio::select! {
+                _ = cancel_token.cancelled() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+}
+
+fn parse_status(value: &Value) -> Aria2Status {
+    // aria2 sends numbers as strings
+    let number = |key: &str| -> u64 {
+        value[key]
+            .as_str()
+            .and_then(|number| number.parse().ok())
+            .unwrap_or(0)
+    };
+    let gid = value["gid"].as_str().unwrap_or_default().to_string();
+    let files: Vec<String> = value["files"]
+        .as_array()
+        .map(|files| {
+            files
+                .iter()
+                .filter_map(|file| file["path"].as_str())
+                .filter(|path| !path.is_empty())
+                .map(|path| path.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let name = value["bittorrent"]["info"]["name"]
+        .as_str()
+        .map(|name| name.to_string())
+        .or_else(|| {
+            files.first().map(|path| {
+                Path::new(path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| path.clone())
+            })
+        })
+        .unwrap_or(gid);
+
+    Aria2Status {
+        status: value["status"].as_str().unwrap_or_default().to_string(),
+        name,
+        total: number("totalLength"),
+        completed: number("completedLength"),
+        followed_by: value["followedBy"]
+            .as_array()
+            .map(|gids| {
+                gids.iter()
+                    .filter_map(|gid| gid.as_str().map(|gid| gid.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        error_message: value["errorMessage"].as_str().map(|e| e.to_string()),
+        files,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_server::{serve, Reply};
+    use std::sync::{Arc, Mutex};
+    use url::Url;
+
+    const MAGNET: &str = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567";
+
+    /// aria2 stand-in, answers with `result` for each method and records the requests
+    async fn stub(results: Vec<(&'static str, Value)>) -> (Aria2Client, Arc<Mutex<Vec<Value>>>) {
+        let requests: Arc<Mutex<Vec<Value>>> = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        let base_url = serve(move |_: &Url, body: &[u8]| {
+            let request: Value = serde_json::from_slice(body).unwrap();
+            recorded.lock().unwrap().push(request.clone());
+            let method = request["method"].as_str().unwrap_or_default();
+            let reply = match results.iter().find(|(name, _)| *name == method) {
+                Some((_, result)) => {
+                    json!({ "jsonrpc": "2.0", "id": request["id"], "result": result })
+                }
+                None => json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": { "code": 1, "message": format!("No such method: {method}") },
+                }),
+            };
+            Reply::json(&reply)
+        })
+        .await;
+        let client = Aria2Client::new(
+            base_url.join("jsonrpc").unwrap().as_str(),
+            Some("secret".to_string()),
+        );
+        (client, requests)
+    }
+
+    #[tokio::test]
+    async fn add_uri() {
+        let (client, requests) = stub(vec![("aria2.addUri", json!("2089b05ecca3d829"))]).await;
+        let gid = client
+            .add_uri(MAGNET, Path::new("/downloads"))
+            .await
+            .unwrap();
+        assert_eq!(gid, "2089b05ecca3d829");
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(request["method"], "aria2.addUri");
+        assert_eq!(
+            request["params"],
+            json!(["token:secret", [MAGNET], { "dir": "/downloads" }])
+        );
+    }
+
+    #[tokio::test]
+    async fn tell_status() {
+        let status = json!({
+            "gid": "2089b05ecca3d829",
+            "status": "active",
+            "totalLength": "1048576",
+            "completedLength": "524288",
+            "bittorrent": { "info": { "name": "Ubuntu ISO" } },
+            "files": [{ "path": "/downloads/ubuntu.iso" }, { "path": "" }],
+        });
+        let (client, requests) = stub(vec![("aria2.tellStatus", status)]).await;
+        let status = client.tell_status("2089b05ecca3d829").await.unwrap();
+        assert_eq!(status.status, "active");
+        assert_eq!(status.name, "Ubuntu ISO");
+        assert_eq!(status.total, 1048576);
+        assert_eq!(status.completed, 524288);
+        assert_eq!(status.files, vec!["/downloads/ubuntu.iso".to_string()]);
+        assert!(status.followed_by.is_empty());
+        assert_eq!(status.error_message, None);
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(
+            request["params"],
+            json!(["token:secret", "2089b05ecca3d829", STATUS_KEYS])
+        );
+    }
+
+    #[tokio::test]
+    async fn remove() {
+        let (client, requests) = stub(vec![("aria2.remove", json!("2089b05ecca3d829"))]).await;
+        client.remove("2089b05ecca3d829").await.unwrap();
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(request["method"], "aria2.remove");
+        assert_eq!(
+            request["params"],
+            json!(["token:secret", "2089b05ecca3d829"])
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_error() {
+        let (client, _) = stub(vec![]).await;
+        let error = client.remove("2089b05ecca3d829").await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "aria2 aria2.remove failed: No such method: aria2.remove"
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_wait_removes_job() {
+        let (client, requests) = stub(vec![
+            (
+                "aria2.tellStatus",
+                json!({ "gid": "a", "status": "active" }),
+            ),
+            ("aria2.remove", json!("a")),
+        ])
+        .await;
+        let cancel_token = CancellationToken::new();
+        cancel_token.cancel();
+        let error = client
+            .wait("a", &cancel_token, &|| false, &|_: &str, _, _| {})
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error.downcast_ref::<BotError>(), Some(BotError::Cancelled)),
+            "{error}"
+        );
+        let methods: Vec<Value> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| request["method"].clone())
+            .collect();
+        assert_eq!(methods, vec![json!("aria2.remove")]);
+    }
+
+    #[tokio::test]
+    async fn interrupted_wait_keeps_job() {
+        let (client, requests) = stub(vec![("aria2.remove", json!("a"))]).await;
+        let cancel_token = CancellationToken::new();
+        cancel_token.cancel();
+        let error = client
+            .wait("a", &cancel_token, &|| true, &|_: &str, _, _| {})
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                error.downcast_ref::<BotError>(),
+                Some(BotError::Interrupted)
+            ),
+            "{error}"
+        );
+        assert!(requests.lock().unwrap().is_empty());
+    }
+}
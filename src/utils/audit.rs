@@ -0,0 +1,199 @@
+use super::json_store::now;
+use crate::app_config::AppConfig;
+use grammers_client::types::Message;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+lazy_static::lazy_static! {
+    /// Keeps lines from concurrent handlers apart while writing and rotating
+    static ref AUDIT_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// One line of `AUDIT_FILE`, fields that don't apply to the action are left out
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: u64,
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    /// Seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    pub result: String,
+}
+
+impl AuditEntry {
+    pub fn new(user_id: i64, chat_id: i64, action: &str) -> Self {
+        AuditEntry {
+            time: now(),
+            user_id,
+            chat_id,
+            action: action.to_string(),
+            result: "ok".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Entry for the sender and chat of `message`
+    pub fn from_message(message: &Message, action: &str) -> Self {
+        let user_id = message.sender().map(|sender| sender.id()).unwrap_or(0);
+        AuditEntry::new(user_id, message.chat().id(), action)
+    }
+
+    pub fn file_name(mut self, file_name: &str) -> Self {
+        self.file_name = Some(file_name.to_string());
+        self
+    }
+
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn destination(mut self, destination: &str) -> Self {
+        self.destination = Some(destination.to_string());
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration.as_secs());
+        self
+    }
+
+    pub fn result(mut self, result: &str) -> Self {
+        self.result = result.to_string();
+        self
+    }
+
+    /// `ok`, `cancelled` or `failed: <error>`
+    pub fn outcome<T, E: Display>(self, result: &Result<T, E>, cancelled: bool) -> Self {
+        match result {
+            Ok(_) => self.result("ok"),
+            Err(_) if cancelled => self.result("cancelled"),
+            Err(error) => self.result(&format!("failed: {error}")),
+        }
+    }
+
+    /// Short line for `/audit`
+    pub fn summary(&self) -> String {
+        let mut line = format!(
+            "{} ago, {}: {}",
+            format_age(now().saturating_sub(self.time)),
+            self.user_id,
+            self.action
+        );
+        if let Some(file_name) = &self.file_name {
+            line.push_str(&format!(" {file_name}"));
+        }
+        if let Some(size) = self.size {
+            line.push_str(&format!(" ({:.1} MB)", size as f64 / (1024.0 * 1024.0)));
+        }
+        if let Some(duration) = self.duration {
+            line.push_str(&format!(" in {duration} sec"));
+        }
+        line.push_str(&format!(" -> {}", self.result));
+        line
+    }
+}
+
+/// Append `entry` to `AUDIT_FILE` as a json line, failures are only logged
+pub fn audit(config: &AppConfig, entry: AuditEntry) {
+    log::info!("Audit: {} {} {}", entry.user_id, entry.action, entry.result);
+
+    let _lock = AUDIT_LOCK.lock().unwrap();
+    rotate(config);
+    let written = serde_json::to_string(&entry)
+        .map_err(|e| e.to_string())
+        .and_then(|line| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.audit_file)
+                .and_then(|mut file| writeln!(file, "{line}"))
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = written {
+        log::error!("Failed to write {}: {e}", config.audit_file);
+    }
+}
+
+/// Newest `count` entries, newest first, reading rotated files when needed
+pub fn recent_entries(config: &AppConfig, count: usize) -> Vec<AuditEntry> {
+    let _lock = AUDIT_LOCK.lock().unwrap();
+    let mut entries: Vec<AuditEntry> = Vec::new();
+    for index in 0..=config.audit_keep {
+        let content = match fs::read_to_string(rotated_path(config, index)) {
+            Ok(content) => content,
+            Err(_) => break,
+        };
+        let parsed = content
+            .lines()
+            .rev()
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok());
+        entries.extend(parsed.take(count - entries.len()));
+        if entries.len() == count {
+            break;
+        }
+    }
+    entries
+}
+
+/// `audit.log` for 0, `audit.log.1` and so on for rotated files
+fn rotated_path(config: &AppConfig, index: usize) -> String {
+    match index {
+        0 => config.audit_file.clone(),
+        _ => format!("{}.{index}", config.audit_file),
+    }
+}
+
+/// Start a new file when the current one is too big or from another day
+fn rotate(config: &AppConfig) {
+    let metadata = match fs::metadata(&config.audit_file) {
+        Ok(metadata) => metadata,
+        Err(_) => return,
+    };
+    let modified_day = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs() / 86400);
+    let too_big = metadata.len() >= config.audit_max_size;
+    let new_day = modified_day.is_some_and(|day| day != now() / 86400);
+    if !too_big && !new_day {
+        return;
+    }
+
+    // Oldest file falls off the end
+    if config.audit_keep == 0 {
+        let _ = fs::remove_file(&config.audit_file);
+        return;
+    }
+    for index in (0..config.audit_keep).rev() {
+        let from = rotated_path(config, index);
+        if fs::metadata(&from).is_ok() {
+            if let Err(e) = fs::rename(&from, rotated_path(config, index + 1)) {
+                log::error!("Failed to rotate {from}: {e}");
+            }
+        }
+    }
+}
+
+/// Short age like `5m` or `3d`
+pub fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
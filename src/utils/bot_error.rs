@@ -0,0 +1,424 @@
+use super::custom_result::ResultGram;
+use grammers_client::types::{Chat, Message};
+use grammers_client::InvocationError;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+use telegram_bot::downloader::DownloadError;
+
+/// Errors users can run into, with a friendly reply and a detailed log message
+#[derive(Debug)]
+pub enum BotError {
+    /// Config file or environment is invalid
+    Config(String),
+    /// Telegram refused a request
+    Telegram(InvocationError),
+    Io(io::Error),
+    /// No space left while writing `path`
+    DiskFull(String),
+    Cancelled,
+    /// Stopped by shutdown, saved to continue after restart
+    Interrupted,
+    /// `what` didn't finish within `after`
+    Timeout {
+        what: String,
+        after: Duration,
+    },
+    /// Not allowed to read or write `path`
+    Permission(String),
+    /// yt-dlp, gdown or aria2 failed
+    ExternalTool {
+        program: String,
+        detail: String,
+    },
+    /// Server of a link couldn't be reached
+    Network(String),
+    /// Server of a link answered with an error status
+    Http {
+        status: u16,
+        detail: String,
+    },
+    /// Stopped after transferring more than the user has left
+    OverLimit,
+    /// Anything else, the user only gets a generic reply
+    Other(String),
+}
+
+/// Languages replies are translated to, English is used for the rest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+    English,
+    German,
+    Spanish,
+}
+
+impl Language {
+    /// `de`, `de-DE` or `es-419` style codes, None if not translated
+    pub fn from_code(code: &str) -> Option<Language> {
+        let code = code.trim().to_lowercase();
+        match code.split(['-', '_']).next().unwrap_or("") {
+            "en" => Some(Language::English),
+            "de" => Some(Language::German),
+            "es" => Some(Language::Spanish),
+            _ => None,
+        }
+    }
+
+    /// Telegram language of the sender, `default` if unknown or not translated
+    pub fn of(message: &Message, default: Language) -> Language {
+        match message.sender() {
+            Some(Chat::User(user)) => user
+                .lang_code()
+                .and_then(Language::from_code)
+                .unwrap_or(default),
+            _ => default,
+        }
+    }
+}
+
+/// Reply texts of one language, `{name}`, `{seconds}` and `{detail}` are filled in
+struct Texts {
+    failed: &'static str,
+    config: &'static str,
+    telegram: &'static str,
+    file_reference_expired: &'static str,
+    flood_wait: &'static str,
+    io: &'static str,
+    disk_full: &'static str,
+    cancelled: &'static str,
+    interrupted: &'static str,
+    timeout: &'static str,
+    permission: &'static str,
+    external_tool: &'static str,
+    network: &'static str,
+    http: &'static str,
+    over_limit: &'static str,
+    other: &'static str,
+}
+
+const ENGLISH: Texts = Texts {
+    failed: "Failed To Download",
+    config: "the bot is not configured correctly, please tell the admin",
+    telegram: "Telegram refused the request ({name}), please try again later",
+    file_reference_expired: "the file link expired, please forward the message again",
+    flood_wait: "Telegram asks to slow down, please try again in {seconds} sec",
+    io: "the file could not be written ({detail})",
+    disk_full: "the disk is full, please free some space or choose another folder",
+    cancelled: "Download Cancelled",
+    interrupted: "Download interrupted by a restart, it will resume",
+    timeout: "{name} took longer than {seconds} sec and was stopped",
+    permission: "the bot is not allowed to write there, please choose another folder",
+    external_tool: "{name} could not download this, please check the link",
+    network: "the server could not be reached, please try again later",
+    http: "the server answered with error {status}, please check the link",
+    over_limit: "the download went over your download limit and was stopped",
+    other: "something went wrong, the admin can find the details in the log",
+};
+
+const GERMAN: Texts = Texts {
+    failed: "Download fehlgeschlagen",
+    config: "der Bot ist falsch eingerichtet, bitte sag dem Admin Bescheid",
+    telegram: "Telegram hat die Anfrage abgelehnt ({name}), bitte versuch es später noch einmal",
+    file_reference_expired:
+        "der Link zur Datei ist abgelaufen, bitte leite die Nachricht noch einmal weiter",
+    flood_wait: "Telegram bremst, bitte versuch es in {seconds} Sek. noch einmal",
+    io: "die Datei konnte nicht geschrieben werden ({detail})",
+    disk_full: "die Festplatte ist voll, bitte schaff Platz oder wähl einen anderen Ordner",
+    cancelled: "Download abgebrochen",
+    interrupted: "Download durch einen Neustart unterbrochen, er wird fortgesetzt",
+    timeout: "{name} hat länger als {seconds} Sek. gebraucht und wurde gestoppt",
+    permission: "der Bot darf dort nicht schreiben, bitte wähl einen anderen Ordner",
+    external_tool: "{name} konnte das nicht herunterladen, bitte prüf den Link",
+    network: "der Server war nicht erreichbar, bitte versuch es später noch einmal",
+    http: "der Server hat mit Fehler {status} geantwortet, bitte prüf den Link",
+    over_limit: "der Download hat dein Limit überschritten und wurde gestoppt",
+    other: "etwas ist schiefgelaufen, der Admin findet die Details im Log",
+};
+
+const SPANISH: Texts = Texts {
+    failed: "La descarga falló",
+    config: "el bot no está bien configurado, avisa al administrador",
+    telegram: "Telegram rechazó la petición ({name}), inténtalo más tarde",
+    file_reference_expired: "el enlace al archivo caducó, reenvía el mensaje otra vez",
+    flood_wait: "Telegram pide esperar, inténtalo de nuevo en {seconds} s",
+    io: "no se pudo escribir el archivo ({detail})",
+    disk_full: "el disco está lleno, libera espacio o elige otra carpeta",
+    cancelled: "Descarga cancelada",
+    interrupted: "Descarga interrumpida por un reinicio, se reanudará",
+    timeout: "{name} tardó más de {seconds} s y se detuvo",
+    permission: "el bot no puede escribir ahí, elige otra carpeta",
+    external_tool: "{name} no pudo descargar esto, revisa el enlace",
+    network: "no se pudo conectar con el servidor, inténtalo más tarde",
+    http: "el servidor respondió con el error {status}, revisa el enlace",
+    over_limit: "la descarga superó tu límite y se detuvo",
+    other: "algo salió mal, el administrador puede ver los detalles en el registro",
+};
+
+impl BotError {
+    /// Reply for the user, short and without internals
+    pub fn user_message(&self, language: Language) -> String {
+        let texts = match language {
+            Language::English => &ENGLISH,
+            Language::German => &GERMAN,
+            Language::Spanish => &SPANISH,
+        };
+        let reason = match self {
+            BotError::Cancelled => return texts.cancelled.to_string(),
+            BotError::Interrupted => return texts.interrupted.to_string(),
+            BotError::Config(_) => texts.config.to_string(),
+            BotError::Telegram(InvocationError::Rpc(rpc)) => match rpc.name.as_str() {
+                "FILE_REFERENCE_EXPIRED" => texts.file_reference_expired.to_string(),
+                "FLOOD_WAIT" => texts
+                    .flood_wait
+                    .replace("{seconds}", &rpc.value.unwrap_or(0).to_string()),
+                name => texts.telegram.replace("{name}", name),
+            },
+            BotError::Telegram(_) => texts.telegram.replace("{name}", "network"),
+            BotError::Io(e) => texts.io.replace("{detail}", &e.to_string()),
+            BotError::DiskFull(_) => texts.disk_full.to_string(),
+            BotError::Timeout { what, after } => texts
+                .timeout
+                .replace("{name}", what)
+                .replace("{seconds}", &after.as_secs().to_string()),
+            BotError::Permission(_) => texts.permission.to_string(),
+            BotError::ExternalTool { program, .. } => {
+                texts.external_tool.replace("{name}", program)
+            }
+            BotError::Network(_) => texts.network.to_string(),
+            BotError::Http { status, .. } => texts.http.replace("{status}", &status.to_string()),
+            BotError::OverLimit => texts.over_limit.to_string(),
+            BotError::Other(_) => texts.other.to_string(),
+        };
+        format!("{}: {reason}", texts.failed)
+    }
+
+    /// Sort an io error into disk full and permission errors, `path` is what was written
+    pub fn from_io(error: io::Error, path: &str) -> BotError {
+        match error.kind() {
+            ErrorKind::StorageFull => BotError::DiskFull(path.to_string()),
+            ErrorKind::PermissionDenied => BotError::Permission(path.to_string()),
+            _ => BotError::Io(error),
+        }
+    }
+
+    /// Reply in the sender's language, the caller logs the details
+    pub async fn reply_to(&self, message: &Message, default_language: Language) -> ResultGram<()> {
+        message
+            .reply(self.user_message(Language::of(message, default_language)))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Detailed message for the log and audit
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::Config(detail) => write!(f, "Invalid config:\n{detail}"),
+            BotError::Telegram(InvocationError::Rpc(rpc)) => write!(
+                f,
+                "Telegram error {} {} (value {:?})",
+                rpc.code, rpc.name, rpc.value
+            ),
+            BotError::Telegram(e) => write!(f, "Telegram request failed: {e}"),
+            BotError::Io(e) => write!(f, "IO error: {e}"),
+            BotError::DiskFull(path) => write!(f, "Disk full while writing {path}"),
+            BotError::Cancelled => write!(f, "Download Cancelled"),
+            BotError::Interrupted => write!(f, "Download interrupted by shutdown"),
+            BotError::Timeout { what, after } => {
+                write!(f, "{what} timed out after {} sec", after.as_secs())
+            }
+            BotError::Permission(path) => write!(f, "Permission denied for {path}"),
+            BotError::ExternalTool { program, detail } => write!(f, "{program} failed: {detail}"),
+            BotError::Network(detail) => write!(f, "Network error: {detail}"),
+            BotError::Http { status, detail } => write!(f, "HTTP error {status}: {detail}"),
+            BotError::OverLimit => write!(f, "Download went over the user's limit"),
+            BotError::Other(detail) => write!(f, "{detail}"),
+        }
+    }
+}
+
+impl Error for BotError {}
+
+impl From<io::Error> for BotError {
+    fn from(error: io::Error) -> Self {
+        BotError::from_io(error, "file")
+    }
+}
+
+impl From<InvocationError> for BotError {
+    fn from(error: InvocationError) -> Self {
+        BotError::Telegram(error)
+    }
+}
+
+impl From<reqwest::Error> for BotError {
+    fn from(error: reqwest::Error) -> Self {
+        match error.status() {
+            Some(status) => BotError::Http {
+                status: status.as_u16(),
+                detail: error.to_string(),
+            },
+            None => BotError::Network(error.to_string()),
+        }
+    }
+}
+
+impl From<DownloadError> for BotError {
+    fn from(error: DownloadError) -> Self {
+        match error {
+            DownloadError::Cancelled => BotError::Cancelled,
+            DownloadError::Telegram(e) => BotError::Telegram(e),
+            DownloadError::Io(e) => BotError::from(e),
+            DownloadError::Worker(detail) => BotError::Other(detail),
+        }
+    }
+}
+
+/// Recover the typed error from `ResultGram`, the rest becomes `Other`
+impl From<Box<dyn Error>> for BotError {
+    fn from(error: Box<dyn Error>) -> Self {
+        let error = match error.downcast::<BotError>() {
+            Ok(error) => return *error,
+            Err(error) => error,
+        };
+        let error = match error.downcast::<io::Error>() {
+            Ok(error) => return BotError::from(*error),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<InvocationError>() {
+            Ok(error) => return BotError::Telegram(*error),
+            Err(error) => error,
+        };
+        let error = match error.downcast::<reqwest::Error>() {
+            Ok(error) => return BotError::from(*error),
+            Err(error) => error,
+        };
+        match error.downcast::<DownloadError>() {
+            Ok(error) => BotError::from(*error),
+            Err(error) => BotError::Other(error.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_server::{serve, Reply};
+
+    #[test]
+    fn replies_hide_details() {
+        let cases = [
+            (
+                BotError::Other("HTTP status client error (404 Not Found) for url".to_string()),
+                "Failed To Download: something went wrong, the admin can find the details in the log",
+            ),
+            (
+                BotError::Network("error sending request for url".to_string()),
+                "Failed To Download: the server could not be reached, please try again later",
+            ),
+            (
+                BotError::Http {
+                    status: 404,
+                    detail: "HTTP status client error (404 Not Found) for url".to_string(),
+                },
+                "Failed To Download: the server answered with error 404, please check the link",
+            ),
+            (
+                BotError::from(DownloadError::Worker("task panicked".to_string())),
+                "Failed To Download: something went wrong, the admin can find the details in the log",
+            ),
+            (
+                BotError::DiskFull("/downloads/movie.mkv".to_string()),
+                "Failed To Download: the disk is full, please free some space or choose another folder",
+            ),
+            (
+                BotError::Permission("/downloads".to_string()),
+                "Failed To Download: the bot is not allowed to write there, please choose another folder",
+            ),
+            (
+                BotError::Config("telegram.api_hash: missing".to_string()),
+                "Failed To Download: the bot is not configured correctly, please tell the admin",
+            ),
+            (
+                BotError::ExternalTool {
+                    program: "yt-dlp".to_string(),
+                    detail: "ERROR: Unsupported URL".to_string(),
+                },
+                "Failed To Download: yt-dlp could not download this, please check the link",
+            ),
+            (
+                BotError::Timeout {
+                    what: "gdown".to_string(),
+                    after: Duration::from_secs(60),
+                },
+                "Failed To Download: gdown took longer than 60 sec and was stopped",
+            ),
+            (
+                BotError::OverLimit,
+                "Failed To Download: the download went over your download limit and was stopped",
+            ),
+            (BotError::Cancelled, "Download Cancelled"),
+            (
+                BotError::Interrupted,
+                "Download interrupted by a restart, it will resume",
+            ),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(error.user_message(Language::English), expected, "{error}");
+        }
+    }
+
+    #[test]
+    fn replies_are_translated() {
+        let error = BotError::Http {
+            status: 403,
+            detail: String::new(),
+        };
+        assert_eq!(
+            error.user_message(Language::German),
+            "Download fehlgeschlagen: der Server hat mit Fehler 403 geantwortet, bitte prüf den Link"
+        );
+        assert_eq!(
+            BotError::Other("detail".to_string()).user_message(Language::Spanish),
+            "La descarga falló: algo salió mal, el administrador puede ver los detalles en el registro"
+        );
+        assert_eq!(Language::from_code("de-AT"), Some(Language::German));
+        assert_eq!(Language::from_code("fr"), None);
+    }
+
+    #[test]
+    fn details_stay_in_the_log() {
+        let detail = "HTTP status client error (404 Not Found) for url";
+        let error = BotError::Other(detail.to_string());
+        assert_eq!(error.to_string(), detail);
+        assert!(!error.user_message(Language::English).contains(detail));
+
+        let error = BotError::from(Box::<dyn Error>::from("invalid url"));
+        assert!(matches!(error, BotError::Other(_)));
+        let io_error = io::Error::new(ErrorKind::StorageFull, "no space");
+        let error = BotError::from(Box::<dyn Error>::from(io_error));
+        assert!(matches!(error, BotError::DiskFull(_)));
+    }
+
+    #[tokio::test]
+    async fn request_errors() {
+        let base = serve(|_, _| Reply::error(404)).await;
+        let error = reqwest::get(base)
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap_err();
+        let error = BotError::from(Box::<dyn Error>::from(error));
+        assert!(
+            matches!(error, BotError::Http { status: 404, .. }),
+            "{error}"
+        );
+
+        // Nothing listens on port 1
+        let error = reqwest::get("http://127.0.0.1:1/").await.unwrap_err();
+        let error = BotError::from(Box::<dyn Error>::from(error));
+        assert!(matches!(error, BotError::Network(_)), "{error}");
+    }
+}
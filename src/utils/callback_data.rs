@@ -0,0 +1,280 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bump when the layout of any kind changes, older buttons are then reported as expired
+const CALLBACK_VERSION: u8 = 3;
+
+const CANCEL_DOWNLOAD_KIND: u8 = 1;
+const USER_ACCESS_KIND: u8 = 2;
+const PROMPT_KIND: u8 = 3;
+const HISTORY_PAGE_KIND: u8 = 4;
+const HISTORY_ENTRY_KIND: u8 = 5;
+
+lazy_static::lazy_static! {
+    /// Changes on every start, buttons of running downloads and prompts die with the process
+    static ref BOOT_ID: u32 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos() ^ duration.as_secs() as u32)
+        .unwrap_or(0);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessAction {
+    Approve,
+    Deny,
+    Block,
+    Revoke,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryAction {
+    Send,
+    Delete,
+    Details,
+}
+
+/// Data of every inline button the bot sends
+/// Encoded as `[version, kind, payload..]`, numbers are little endian
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CallbackData {
+    CancelDownload {
+        download_id: u64,
+    },
+    UserAccess {
+        action: AccessAction,
+        user_id: i64,
+    },
+    Prompt {
+        waiter_id: u64,
+        option: u16,
+    },
+    /// History survives restarts, so its buttons don't expire with the process
+    HistoryPage {
+        page: u32,
+    },
+    HistoryEntry {
+        action: HistoryAction,
+        entry_id: u64,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CallbackError {
+    /// From another version or an earlier run of the bot
+    Expired,
+    Invalid,
+}
+
+impl AccessAction {
+    fn to_byte(self) -> u8 {
+        match self {
+            AccessAction::Approve => 0,
+            AccessAction::Deny => 1,
+            AccessAction::Block => 2,
+            AccessAction::Revoke => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(AccessAction::Approve),
+            1 => Some(AccessAction::Deny),
+            2 => Some(AccessAction::Block),
+            3 => Some(AccessAction::Revoke),
+            _ => None,
+        }
+    }
+}
+
+impl HistoryAction {
+    fn to_byte(self) -> u8 {
+        match self {
+            HistoryAction::Send => 0,
+            HistoryAction::Delete => 1,
+            HistoryAction::Details => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(HistoryAction::Send),
+            1 => Some(HistoryAction::Delete),
+            2 => Some(HistoryAction::Details),
+            _ => None,
+        }
+    }
+}
+
+impl CallbackData {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![CALLBACK_VERSION];
+        match self {
+            CallbackData::CancelDownload { download_id } => {
+                data.push(CANCEL_DOWNLOAD_KIND);
+                data.extend_from_slice(&BOOT_ID.to_le_bytes());
+                data.extend_from_slice(&download_id.to_le_bytes());
+            }
+            CallbackData::UserAccess { action, user_id } => {
+                data.push(USER_ACCESS_KIND);
+                data.push(action.to_byte());
+                data.extend_from_slice(&user_id.to_le_bytes());
+            }
+            CallbackData::Prompt { waiter_id, option } => {
+                data.push(PROMPT_KIND);
+                data.extend_from_slice(&BOOT_ID.to_le_bytes());
+                data.extend_from_slice(&waiter_id.to_le_bytes());
+                data.extend_from_slice(&option.to_le_bytes());
+            }
+            CallbackData::HistoryPage { page } => {
+                data.push(HISTORY_PAGE_KIND);
+                data.extend_from_slice(&page.to_le_bytes());
+            }
+            CallbackData::HistoryEntry { action, entry_id } => {
+                data.push(HISTORY_ENTRY_KIND);
+                data.push(action.to_byte());
+                data.extend_from_slice(&entry_id.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, CallbackError> {
+        let (version, kind, mut payload) = match data {
+            [version, kind, payload @ ..] => (*version, *kind, Payload(payload)),
+            _ => return Err(CallbackError::Invalid),
+        };
+        if version != CALLBACK_VERSION {
+            return Err(CallbackError::Expired);
+        }
+
+        let data = match kind {
+            CANCEL_DOWNLOAD_KIND => {
+                payload.boot()?;
+                CallbackData::CancelDownload {
+                    download_id: u64::from_le_bytes(payload.bytes()?),
+                }
+            }
+            USER_ACCESS_KIND => CallbackData::UserAccess {
+                action: AccessAction::from_byte(payload.u8()?).ok_or(CallbackError::Invalid)?,
+                user_id: i64::from_le_bytes(payload.bytes()?),
+            },
+            PROMPT_KIND => {
+                payload.boot()?;
+                CallbackData::Prompt {
+                    waiter_id: u64::from_le_bytes(payload.bytes()?),
+                    option: u16::from_le_bytes(payload.bytes()?),
+                }
+            }
+            HISTORY_PAGE_KIND => CallbackData::HistoryPage {
+                page: u32::from_le_bytes(payload.bytes()?),
+            },
+            HISTORY_ENTRY_KIND => CallbackData::HistoryEntry {
+                action: HistoryAction::from_byte(payload.u8()?).ok_or(CallbackError::Invalid)?,
+                entry_id: u64::from_le_bytes(payload.bytes()?),
+            },
+            _ => return Err(CallbackError::Invalid),
+        };
+        Ok(data)
+    }
+}
+
+/// Reads the payload from the front
+struct Payload<'a>(&'a [u8]);
+
+impl Payload<'_> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], CallbackError> {
+        if self.0.len() < N {
+            return Err(CallbackError::Invalid);
+        }
+        let (bytes, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, CallbackError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    /// Buttons from an earlier run of the bot are expired
+    fn boot(&mut self) -> Result<(), CallbackError> {
+        match u32::from_le_bytes(self.bytes()?) == *BOOT_ID {
+            true => Ok(()),
+            false => Err(CallbackError::Expired),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_kinds() -> Vec<CallbackData> {
+        vec![
+            CallbackData::CancelDownload { download_id: 42 },
+            CallbackData::UserAccess {
+                action: AccessAction::Block,
+                user_id: -100123,
+            },
+            CallbackData::Prompt {
+                waiter_id: 7,
+                option: 300,
+            },
+            CallbackData::HistoryPage { page: 3 },
+            CallbackData::HistoryEntry {
+                action: HistoryAction::Delete,
+                entry_id: 9,
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for data in all_kinds() {
+            assert_eq!(CallbackData::decode(&data.encode()), Ok(data));
+        }
+    }
+
+    #[test]
+    fn other_version_is_expired() {
+        for data in all_kinds() {
+            let mut encoded = data.encode();
+            encoded[0] = CALLBACK_VERSION - 1;
+            assert_eq!(CallbackData::decode(&encoded), Err(CallbackError::Expired));
+        }
+    }
+
+    #[test]
+    fn earlier_run_is_expired() {
+        let kinds = [
+            CallbackData::CancelDownload { download_id: 42 },
+            CallbackData::Prompt {
+                waiter_id: 7,
+                option: 1,
+            },
+        ];
+        for data in kinds {
+            let mut encoded = data.encode();
+            let boot_id = BOOT_ID.wrapping_add(1).to_le_bytes();
+            encoded[2..6].copy_from_slice(&boot_id);
+            assert_eq!(CallbackData::decode(&encoded), Err(CallbackError::Expired));
+        }
+    }
+
+    #[test]
+    fn truncated_is_invalid() {
+        for data in all_kinds() {
+            let encoded = data.encode();
+            for length in 0..encoded.len() {
+                let result = CallbackData::decode(&encoded[..length]);
+                assert_eq!(
+                    result,
+                    Err(CallbackError::Invalid),
+                    "{data:?} cut at {length}"
+                );
+            }
+        }
+        assert_eq!(
+            CallbackData::decode(&[CALLBACK_VERSION, 99]),
+            Err(CallbackError::Invalid)
+        );
+    }
+}
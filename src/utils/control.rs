@@ -0,0 +1,133 @@
+use super::custom_result::ResultGram;
+use super::download_registry;
+
+/// Listen on a local socket, so the command line can see and cancel downloads of the running bot
+/// A request is one line, `list` or `cancel <id>`, the answer is text until the socket closes
+#[cfg(unix)]
+pub fn serve(path: &str) -> ResultGram<()> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    // Left behind if the bot didn't stop cleanly
+    if std::path::Path::new(path).exists() {
+        std::fs::remove_file(path)?;
+    }
+    // Only the user running the bot can cancel downloads, the socket is bound in a private
+    // directory and moved into place once it's closed to others
+    let private_directory = format!("{path}.d");
+    if std::path::Path::new(&private_directory).exists() {
+        std::fs::remove_dir_all(&private_directory)?;
+    }
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_directory)?;
+    let bound = format!("{private_directory}/socket");
+    let listener = UnixListener::bind(&bound)?;
+    std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+    std::fs::rename(&bound, path)?;
+    std::fs::remove_dir(&private_directory)?;
+    log::info!("Listening for commands on {path}");
+
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!("Control socket failed: {e}");
+                    return;
+                }
+            };
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.split();
+                let mut request = String::new();
+                if let Err(e) = BufReader::new(reader).read_line(&mut request).await {
+                    log::warn!("Bad control request: {e}");
+                    return;
+                }
+                let answer = answer(request.trim());
+                if let Err(e) = writer.write_all(answer.as_bytes()).await {
+                    log::warn!("Failed to answer control request: {e}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve(_path: &str) -> ResultGram<()> {
+    log::info!("Control socket needs unix, queue commands are not available");
+    Ok(())
+}
+
+/// Send `request` to the running bot and return its answer
+#[cfg(unix)]
+pub async fn request(path: &str, request: &str) -> ResultGram<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    let mut stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| format!("Can't reach the bot at {path}, is it running? {e}"))?;
+    stream.write_all(format!("{request}\n").as_bytes()).await?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).await?;
+    Ok(answer)
+}
+
+#[cfg(not(unix))]
+pub async fn request(_path: &str, _request: &str) -> ResultGram<String> {
+    Err("Queue commands need a unix socket, not available on this system".into())
+}
+
+fn answer(request: &str) -> String {
+    let mut words = request.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some("list"), None) => {
+            let downloads = download_registry::list();
+            if downloads.is_empty() {
+                return "No downloads running\n".to_string();
+            }
+            downloads
+                .iter()
+                .map(|download| {
+                    format!(
+                        "{}. {} by {}\n",
+                        download.id,
+                        download.summary(),
+                        download.owner_name
+                    )
+                })
+                .collect()
+        }
+        (Some("cancel"), Some(id)) => match id.parse::<u64>() {
+            Ok(id) => match download_registry::cancel(id) {
+                Some(download) => format!("Cancelling {}\n", download.name),
+                None => format!("No running download with id {id}\n"),
+            },
+            Err(_) => format!("Invalid download id {id}\n"),
+        },
+        _ => format!("Unknown request {request}, use list or cancel <id>\n"),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn socket_is_private() {
+        let path = std::env::temp_dir().join(format!("control-{}.sock", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        serve(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!std::path::Path::new(&format!("{path}.d")).exists());
+
+        let answer = request(&path, "cancel 0").await.unwrap();
+        assert!(!answer.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
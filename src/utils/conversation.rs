@@ -0,0 +1,140 @@
+use super::callback_data::CallbackData;
+use grammers_client::types::{CallbackQuery, Message};
+use grammers_client::Update;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+lazy_static::lazy_static! {
+    /// Handlers waiting for a reply, oldest first
+    static ref WAITERS: Mutex<Vec<Waiter>> = Mutex::new(Vec::new());
+    static ref NEXT_WAITER_ID: AtomicU64 = AtomicU64::new(1);
+}
+
+enum Waiting {
+    Message(oneshot::Sender<Message>),
+    Query(oneshot::Sender<CallbackQuery>),
+}
+
+struct Waiter {
+    id: u64,
+    chat_id: i64,
+    /// Only this user can answer, anyone in the chat if None
+    user_id: Option<i64>,
+    waiting: Waiting,
+}
+
+/// Reply a handler is waiting for, stops waiting when dropped
+pub struct Expected<T> {
+    id: u64,
+    receiver: oneshot::Receiver<T>,
+}
+
+impl<T> Expected<T> {
+    /// None on timeout
+    pub async fn wait(mut self, timeout: Duration) -> Option<T> {
+        tokio::time::timeout(timeout, &mut self.receiver)
+            .await
+            .ok()?
+            .ok()
+    }
+}
+
+impl Expected<CallbackQuery> {
+    /// Button data that answers this prompt with `option`
+    pub fn button_data(&self, option: u16) -> Vec<u8> {
+        CallbackData::Prompt {
+            waiter_id: self.id,
+            option,
+        }
+        .encode()
+    }
+}
+
+impl<T> Drop for Expected<T> {
+    fn drop(&mut self) {
+        WAITERS
+            .lock()
+            .unwrap()
+            .retain(|waiter| waiter.id != self.id);
+    }
+}
+
+/// Wait for the next message in `chat_id`, register before sending the prompt
+pub fn expect_message(chat_id: i64, user_id: Option<i64>) -> Expected<Message> {
+    let (sender, receiver) = oneshot::channel();
+    let id = register(chat_id, user_id, Waiting::Message(sender));
+    Expected { id, receiver }
+}
+
+/// Wait for a button press on a prompt built with `button_data`
+pub fn expect_query(chat_id: i64, user_id: Option<i64>) -> Expected<CallbackQuery> {
+    let (sender, receiver) = oneshot::channel();
+    let id = register(chat_id, user_id, Waiting::Query(sender));
+    Expected { id, receiver }
+}
+
+fn register(chat_id: i64, user_id: Option<i64>, waiting: Waiting) -> u64 {
+    let id = NEXT_WAITER_ID.fetch_add(1, Ordering::Relaxed);
+    WAITERS.lock().unwrap().push(Waiter {
+        id,
+        chat_id,
+        user_id,
+        waiting,
+    });
+    id
+}
+
+/// Give the update to a waiting handler, returns it back if nobody waits for it
+/// Commands are never taken, they always start a new handler
+pub fn route(update: Update) -> Option<Update> {
+    match update {
+        Update::NewMessage(message) if !message.outgoing() && !message.text().starts_with('/') => {
+            let chat_id = message.chat().id();
+            let user_id = message.sender().map(|sender| sender.id());
+            let waiter = take_waiter(|waiter| {
+                matches!(waiter.waiting, Waiting::Message(_))
+                    && waiter.chat_id == chat_id
+                    && (waiter.user_id.is_none() || waiter.user_id == user_id)
+            });
+            match waiter.map(|waiter| waiter.waiting) {
+                Some(Waiting::Message(sender)) => {
+                    sender.send(message).err().map(Update::NewMessage)
+                }
+                _ => Some(Update::NewMessage(message)),
+            }
+        }
+        Update::CallbackQuery(query) => {
+            let id = match CallbackData::decode(query.data()) {
+                Ok(CallbackData::Prompt { waiter_id, .. }) => waiter_id,
+                _ => return Some(Update::CallbackQuery(query)),
+            };
+            let user_id = query.sender().id();
+            let waiter = take_waiter(|waiter| {
+                waiter.id == id
+                    && matches!(waiter.waiting, Waiting::Query(_))
+                    && (waiter.user_id.is_none() || waiter.user_id == Some(user_id))
+            });
+            match waiter.map(|waiter| waiter.waiting) {
+                Some(Waiting::Query(sender)) => sender.send(query).err().map(Update::CallbackQuery),
+                _ => Some(Update::CallbackQuery(query)),
+            }
+        }
+        update => Some(update),
+    }
+}
+
+fn take_waiter(matches: impl Fn(&Waiter) -> bool) -> Option<Waiter> {
+    let mut waiters = WAITERS.lock().unwrap();
+    let position = waiters.iter().position(matches)?;
+    Some(waiters.remove(position))
+}
+
+/// Option chosen on a prompt button
+pub fn chosen_option(query: &CallbackQuery) -> Option<u16> {
+    match CallbackData::decode(query.data()) {
+        Ok(CallbackData::Prompt { option, .. }) => Some(option),
+        _ => None,
+    }
+}
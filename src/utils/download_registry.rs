@@ -0,0 +1,255 @@
+use super::bot_error::BotError;
+use super::callback_data::CallbackData;
+use super::download_utils::SharedProgress;
+use super::quota::ActiveDownload;
+use grammers_client::types::Message;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+lazy_static::lazy_static! {
+    static ref DOWNLOADS: Mutex<HashMap<u64, Entry>> = Mutex::new(HashMap::new());
+    static ref NEXT_DOWNLOAD_ID: AtomicU64 = AtomicU64::new(1);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DownloadState {
+    Running,
+    Cancelling,
+    /// Stopped by shutdown, resumed after restart if possible
+    Interrupted,
+}
+
+struct Entry {
+    owner_id: i64,
+    owner_name: String,
+    started: Instant,
+    state: DownloadState,
+    progress: Arc<SharedProgress>,
+    cancel_token: CancellationToken,
+}
+
+/// Snapshot of a running download
+#[derive(Clone)]
+pub struct DownloadInfo {
+    pub id: u64,
+    pub owner_id: i64,
+    pub owner_name: String,
+    pub name: String,
+    pub state: DownloadState,
+    pub downloaded: u64,
+    pub total: u64,
+    pub elapsed: Duration,
+}
+
+impl DownloadInfo {
+    pub fn percent(&self) -> f64 {
+        match self.total {
+            0 => 0.0,
+            total => self.downloaded as f64 / total as f64 * 100.0,
+        }
+    }
+
+    /// Average bytes per second since the download started
+    pub fn speed(&self) -> f64 {
+        self.downloaded as f64 / self.elapsed.as_secs_f64().max(1.0)
+    }
+
+    /// None while size or speed is unknown
+    pub fn eta(&self) -> Option<Duration> {
+        let speed = self.speed();
+        if self.total == 0 || speed <= 0.0 {
+            return None;
+        }
+        let left = self.total.saturating_sub(self.downloaded) as f64;
+        Some(Duration::from_secs_f64(left / speed))
+    }
+
+    /// e.g. `movie.mkv: 17.1% of 700.0 MB, 3.2 MB/s, ETA 3m 05s`
+    pub fn summary(&self) -> String {
+        let mut line = format!(
+            "{}: {:.1}% of {:.1} MB, {:.1} MB/s",
+            self.name,
+            self.percent(),
+            self.total as f64 / (1024.0 * 1024.0),
+            self.speed() / (1024.0 * 1024.0)
+        );
+        if let Some(eta) = self.eta() {
+            let seconds = eta.as_secs();
+            line.push_str(&format!(", ETA {}m {:02}s", seconds / 60, seconds % 60));
+        }
+        match self.state {
+            DownloadState::Running => {}
+            DownloadState::Cancelling => line.push_str(" (cancelling)"),
+            DownloadState::Interrupted => line.push_str(" (interrupted)"),
+        }
+        line
+    }
+}
+
+/// Registered download, it's removed from the registry when dropped
+pub struct Download {
+    id: u64,
+    progress: Arc<SharedProgress>,
+    cancel_token: CancellationToken,
+    /// Bytes written without gaps, where an interrupted download continues
+    checkpoint: Arc<AtomicU64>,
+    /// Quota of the owner, transferred bytes are recorded when the download ends
+    quota: Option<ActiveDownload>,
+}
+
+impl Download {
+    /// Register a download started by the sender of `message`, admitted by `quota::admit`
+    /// It's stopped once it transfers more than the user has left
+    pub fn register(message: &Message, name: &str, quota: ActiveDownload) -> Self {
+        let owner_id = message.sender().map(|sender| sender.id()).unwrap_or(0);
+        let owner_name = message
+            .sender()
+            .map(|sender| sender.name().to_string())
+            .unwrap_or_default();
+        let mut download = Download::new(owner_id, &owner_name, name);
+        download.progress.set_limit(quota.limit());
+        download.quota = Some(quota);
+        download
+    }
+
+    /// Register a download without a chat, like from the command line
+    pub fn new(owner_id: i64, owner_name: &str, name: &str) -> Self {
+        let id = NEXT_DOWNLOAD_ID.fetch_add(1, Ordering::Relaxed);
+        let progress = Arc::new(SharedProgress::default());
+        progress.set(name, 0, 0);
+        let cancel_token = progress.limit_token().child_token();
+
+        DOWNLOADS.lock().unwrap().insert(
+            id,
+            Entry {
+                owner_id,
+                owner_name: owner_name.to_string(),
+                started: Instant::now(),
+                state: DownloadState::Running,
+                progress: progress.clone(),
+                cancel_token: cancel_token.clone(),
+            },
+        );
+        log::debug!("Registered download {id}: {name}");
+        Download {
+            id,
+            progress,
+            cancel_token,
+            checkpoint: Arc::new(AtomicU64::new(0)),
+            quota: None,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn progress(&self) -> &Arc<SharedProgress> {
+        &self.progress
+    }
+
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.cancel_token
+    }
+
+    pub fn checkpoint(&self) -> &Arc<AtomicU64> {
+        &self.checkpoint
+    }
+
+    /// Cancel button or `/cancel`, not the quota running out
+    pub fn is_cancelled_by_user(&self) -> bool {
+        self.cancel_token.is_cancelled() && !self.progress.is_over_limit()
+    }
+
+    /// Error of the download, says so when it was stopped by the quota
+    pub fn error(&self, error: impl Into<BotError>) -> BotError {
+        match error.into() {
+            BotError::Cancelled if self.progress.is_over_limit() => BotError::OverLimit,
+            error => error,
+        }
+    }
+
+    /// Don't count the transferred bytes, the download starts again from zero after restart
+    pub fn discard_usage(&mut self) {
+        self.quota = None;
+    }
+
+    /// Stopped by shutdown instead of the user
+    pub fn is_interrupted(&self) -> bool {
+        DOWNLOADS
+            .lock()
+            .unwrap()
+            .get(&self.id)
+            .is_some_and(|entry| entry.state == DownloadState::Interrupted)
+    }
+
+    /// Data of the Cancel button
+    pub fn button_data(&self) -> Vec<u8> {
+        CallbackData::CancelDownload {
+            download_id: self.id,
+        }
+        .encode()
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        DOWNLOADS.lock().unwrap().remove(&self.id);
+        if let Some(quota) = self.quota.take() {
+            quota.record(self.progress.transferred());
+        }
+        log::debug!("Unregistered download {}", self.id);
+    }
+}
+
+/// Ask a download to stop, None if it already finished
+pub fn cancel(id: u64) -> Option<DownloadInfo> {
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    let entry = downloads.get_mut(&id)?;
+    entry.state = DownloadState::Cancelling;
+    entry.cancel_token.cancel();
+    Some(info(id, entry))
+}
+
+/// Stop every download for shutdown, returns how many were running
+pub fn interrupt_all() -> usize {
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    for entry in downloads.values_mut() {
+        entry.state = DownloadState::Interrupted;
+        entry.cancel_token.cancel();
+    }
+    downloads.len()
+}
+
+pub fn get(id: u64) -> Option<DownloadInfo> {
+    let downloads = DOWNLOADS.lock().unwrap();
+    downloads.get(&id).map(|entry| info(id, entry))
+}
+
+/// Running downloads, oldest first
+pub fn list() -> Vec<DownloadInfo> {
+    let downloads = DOWNLOADS.lock().unwrap();
+    let mut all: Vec<DownloadInfo> = downloads
+        .iter()
+        .map(|(id, entry)| info(*id, entry))
+        .collect();
+    all.sort_by_key(|download| download.id);
+    all
+}
+
+fn info(id: u64, entry: &Entry) -> DownloadInfo {
+    let (name, downloaded, total) = entry.progress.get();
+    DownloadInfo {
+        id,
+        owner_id: entry.owner_id,
+        owner_name: entry.owner_name.clone(),
+        name,
+        state: entry.state,
+        downloaded,
+        total,
+        elapsed: entry.started.elapsed(),
+    }
+}
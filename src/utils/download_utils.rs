@@ -1,212 +1,105 @@
+use super::bot_error::BotError;
 use super::custom_result::ResultGram;
+use super::download_registry::Download;
+use super::progress::ProgressReporter;
+use crate::app_config::AppConfig;
 use crate::Client;
-use grammers_client::client::files::MAX_CHUNK_SIZE;
-use grammers_client::types::Media;
 use grammers_client::types::Message;
-use grammers_client::{button, grammers_tl_types, reply_markup, InputMessage, InvocationError};
-use grammers_tl_types as tl;
 use std::{
-    io::SeekFrom,
-    sync::atomic::{AtomicI64, Ordering},
-    sync::Arc,
-    time::Instant,
-};
-use tokio::sync::mpsc::unbounded_channel;
-use tokio::{
-    fs,
-    io::{self, AsyncSeekExt, AsyncWriteExt},
+    path::{Component, Path},
+    sync::atomic::{AtomicU64, Ordering},
 };
+use telegram_bot::downloader::{Downloader, DownloaderBuilder};
+use tokio::fs;
 use tokio_util::sync::CancellationToken;
 
-/// Modified Version of `download_media_concurrent` from library
-/// Implement Cancellation of Download, and sends DownloadProgress to user
+/// Parallel requests when the caller doesn't choose
+const DEFAULT_WORKERS: usize = 4;
+
+/// Download media of `message` with `workers` parallel requests, replies with progress and a Cancel button
+/// Bytes before `resume_from` are kept from an interrupted download, 0 starts over
 pub async fn download_media_concurrent(
     bot: Client,
     path: String,
     workers: usize,
     message: Message,
-    button_id: &[u8],
-    cancel_token: CancellationToken,
+    download: &Download,
+    resume_from: u64,
 ) -> ResultGram<()> {
-    let media = message.media().unwrap();
-    let document = match media.clone() {
-        Media::Document(document) => document,
-        _ => panic!("Only Document type is supported!"),
-    };
-    let size = document.size();
-    let location = media.to_raw_input_location().unwrap();
-
-    let message_reply = message
-        .reply(
-            InputMessage::text("Downloading..").reply_markup(&reply_markup::inline(vec![vec![
-                button::inline("Cancel", button_id),
-            ]])),
-        )
-        .await?;
+    let downloader = media_downloader(bot, &message, Path::new(&path), workers, download)
+        .await?
+        .resume_from(resume_from)
+        .build();
+    let config = AppConfig::current();
+    let mut reporter = ProgressReporter::for_chat(&message, download, &config).await?;
+    let result = reporter
+        .run(downloader.run())
+        .await
+        .map_err(|e| download.error(e));
+    if result.is_err() && download.is_interrupted() {
+        reporter.interrupt().await;
+        return Err(BotError::Interrupted.into());
+    }
+    reporter.finish(&result).await;
+    result?;
+    Ok(())
+}
 
-    // Allocate
-    let mut file = fs::File::create(path.clone()).await?;
-    file.set_len(size as u64).await?;
-    file.seek(SeekFrom::Start(0)).await?;
-
-    // Start workers
-    let (tx, mut rx) = unbounded_channel();
-    let part_index = Arc::new(tokio::sync::Mutex::new(0));
-    let downloaded_size = Arc::new(AtomicI64::new(0));
-    let mut tasks: Vec<tokio::task::JoinHandle<Result<(), InvocationError>>> = vec![];
-
-    for _ in 0..workers {
-        let location = location.clone();
-        let tx = tx.clone();
-        let part_index = part_index.clone();
-        let client = bot.clone();
-        let downloaded_size = downloaded_size.clone();
-        let cancellation_token = cancel_token.clone();
-
-        let task = tokio::task::spawn(async move {
-            let mut retry_offset = None;
-            let mut dc = None;
-            loop {
-                if cancellation_token.is_cancelled() {
-                    return Ok(());
-                }
-                // Calculate file offset
-                let offset: u64 = {
-                    if let Some(offset) = retry_offset {
-                        retry_offset = None;
-                        offset
-                    } else {
-                        let mut i = part_index.lock().await;
-                        *i += 1;
-                        (MAX_CHUNK_SIZE as u64) * (*i - 1)
-                    }
-                };
-                if (offset as i64) > size {
-                    break;
-                }
-                // Fetch from telegram
-                let request = &tl::functions::upload::GetFile {
-                    precise: true,
-                    cdn_supported: false,
-                    location: location.clone(),
-                    offset: offset as i64,
-                    limit: MAX_CHUNK_SIZE,
-                };
-                let res = match dc {
-                    None => client.invoke(request).await,
-                    Some(dc) => client.invoke_in_dc(request, dc as i32).await,
-                };
-                match res {
-                    Ok(tl::enums::upload::File::File(file)) => {
-                        downloaded_size.fetch_add(file.bytes.len() as i64, Ordering::SeqCst);
-                        tx.send((offset as u64, file.bytes)).unwrap();
-                    }
-                    Ok(tl::enums::upload::File::CdnRedirect(_)) => {
-                        panic!("API returned File::CdnRedirect even though cdn_supported = false");
-                    }
-                    Err(InvocationError::Rpc(err)) => {
-                        // File Migrate Error
-                        if err.code == 303 {
-                            dc = err.value;
-                            retry_offset = Some(offset);
-                            continue;
-                        }
-                        return Err(InvocationError::Rpc(err));
-                    }
-                    Err(e) => return Err(e),
-                }
-            }
-            Ok::<(), InvocationError>(())
-        });
-        tasks.push(task);
-    }
-    drop(tx);
-
-    // File write loop
-    let mut last_update_time = Instant::now();
-    let mut last_downloaded_size = 0;
-    let mut last_progress_text: String = "".to_string();
-
-    let mut pos = 0;
-    while let Some((offset, data)) = rx.recv().await {
-        if cancel_token.is_cancelled() {
-            for task in tasks {
-                task.abort();
-            }
-            message_reply.delete().await?;
-            return Err(Box::new(io::Error::new(
-                io::ErrorKind::Other,
-                "Download Cancelled",
-            )));
-        }
+/// Engine for the file of `message`, progress goes to the registered download
+/// The message is fetched again if its file reference expires during the download
+async fn media_downloader(
+    bot: Client,
+    message: &Message,
+    path: &Path,
+    workers: usize,
+    download: &Download,
+) -> ResultGram<DownloaderBuilder<fs::File>> {
+    let builder =
+        Downloader::for_message(bot, message).ok_or("Only Document type is supported!")?;
 
-        if offset != pos {
-            file.seek(SeekFrom::Start(offset)).await?;
-        }
-        file.write_all(&data).await?;
-        pos = offset + data.len() as u64;
-
-        // Update progress every 5 sec
-        if last_update_time.elapsed().as_secs() >= 5 {
-            let downloaded = downloaded_size.load(Ordering::SeqCst) as f64;
-            let speed_mbps = ((downloaded - last_downloaded_size as f64) / (1024.0 * 1024.0))
-                / last_update_time.elapsed().as_secs_f64();
-            last_downloaded_size = downloaded as usize;
-            last_update_time = Instant::now();
-
-            let progress_text =
-                format_message(document.name(), downloaded, size as f64, speed_mbps);
-
-            if last_progress_text != progress_text {
-                message_reply
-                    .edit(InputMessage::text(progress_text.clone()).reply_markup(
-                        &reply_markup::inline(vec![vec![button::inline("Cancel", button_id)]]),
-                    ))
-                    .await?;
-                last_progress_text = progress_text;
-            }
-        }
-    }
+    // Allocate, an existing file is kept so an interrupted download can continue
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)
+        .await?;
+    file.set_len(builder.size()).await?;
 
-    // Final update to indicate completion
-    message_reply.delete().await?;
+    // Name the download was registered with, may differ from the document name
+    let (name, _, _) = download.progress().get();
+    let progress = download.progress().clone();
+    Ok(builder
+        .writer(file)
+        .workers(workers)
+        .cancel_token(download.cancel_token().clone())
+        .checkpoint(download.checkpoint().clone())
+        .on_progress(move |downloaded, total| progress.set(&name, downloaded, total)))
+}
 
-    // Check if all tasks finished succesfully
-    for task in tasks {
-        task.await?
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+/// Download small files like torrents without touching the disk
+pub async fn download_to_memory(bot: Client, message: Message) -> ResultGram<Vec<u8>> {
+    let media = message.media().ok_or("Message has no media")?;
+    let mut download = bot.iter_download(&media);
+    let mut bytes: Vec<u8> = Vec::new();
+    while let Some(chunk) = download.next().await? {
+        bytes.extend(chunk);
     }
-    Ok(())
+    Ok(bytes)
 }
 
-/// Format the message sent to Bot
-fn format_message(name: &str, downloaded_size: f64, total_size: f64, speed: f64) -> String {
-    let bar_width = 10;
-
-    let progress = if total_size > 0.0 {
-        (downloaded_size / total_size) * 100.0
-    } else {
-        0.0
-    };
-
-    let filled_blocks: usize = (progress / 100.0 * bar_width as f64).round() as usize;
-    let empty_blocks = bar_width - filled_blocks;
-
-    let progress_bar = format!(
-        "[{}] {:.2}%",
-        "🟩".repeat(filled_blocks).to_string() + &"⬜".repeat(empty_blocks),
-        progress
-    );
-
-    return format!(
-        "Downloading {name}
-        \n{:.1} MB of {:.2} MB done.\n\n{}
-        \nSpeed {:.1} MB/s",
-        downloaded_size / (1024.0 * 1024.0),
-        total_size / (1024.0 * 1024.0),
-        progress_bar,
-        speed,
-    );
+/// Download media of `message` into `path` without writing anything to the chat
+pub async fn download_media_to_file(
+    bot: Client,
+    message: Message,
+    path: &Path,
+    download: &Download,
+) -> ResultGram<()> {
+    let downloader = media_downloader(bot, &message, path, DEFAULT_WORKERS, download)
+        .await?
+        .build();
+    downloader.run().await.map_err(BotError::from)?;
+    Ok(())
 }
 
 pub async fn delete_file(path: String) {
@@ -216,3 +109,167 @@ pub async fn delete_file(path: String) {
         log::info!("File deleted successfully")
     }
 }
+
+/// Only the file name part of a name from Telegram, a user or a server
+/// None if nothing usable is left, like for `..`
+pub fn plain_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    if name.is_empty() || name == "." || name == ".." || name.contains('\0') {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// `directory/name`, None unless the file stays directly inside `directory`
+pub fn path_in(directory: &str, name: &str) -> Option<String> {
+    let name = plain_file_name(name)?;
+    let mut components = Path::new(&name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => {}
+        _ => return None,
+    }
+    let path = Path::new(directory).join(&name);
+    if path.parent() != Some(Path::new(directory)) {
+        return None;
+    }
+    Some(path.to_string_lossy().to_string())
+}
+
+/// Progress shared between a running download and the task reporting it
+#[derive(Default)]
+pub struct SharedProgress {
+    name: std::sync::Mutex<String>,
+    downloaded: AtomicU64,
+    total: AtomicU64,
+    /// Bytes received over all files and streams, counted against the quota
+    transferred: AtomicU64,
+    /// Most bytes allowed to transfer, 0 for unlimited
+    limit: AtomicU64,
+    /// Cancelled once `limit` is passed
+    over_limit: CancellationToken,
+}
+
+impl SharedProgress {
+    /// `downloaded` restarts from 0 for every file or stream of a download, the difference is counted
+    pub fn set(&self, name: &str, downloaded: u64, total: u64) {
+        let mut current_name = self.name.lock().unwrap();
+        let last = self.downloaded.swap(downloaded, Ordering::SeqCst);
+        let received = if current_name.as_str() != name || downloaded < last {
+            *current_name = name.to_string();
+            downloaded
+        } else {
+            downloaded - last
+        };
+        self.total.store(total, Ordering::SeqCst);
+
+        let transferred = self.transferred.fetch_add(received, Ordering::SeqCst) + received;
+        let limit = self.limit.load(Ordering::SeqCst);
+        if limit > 0 && transferred > limit && !self.over_limit.is_cancelled() {
+            log::info!("{name} passed its limit of {limit} bytes, stopping");
+            self.over_limit.cancel();
+        }
+    }
+
+    /// Continue from `downloaded` bytes kept from before, they don't count as transferred
+    pub fn resume_at(&self, downloaded: u64) {
+        self.downloaded.store(downloaded, Ordering::SeqCst);
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::SeqCst);
+    }
+
+    pub fn transferred(&self) -> u64 {
+        self.transferred.load(Ordering::SeqCst)
+    }
+
+    pub fn is_over_limit(&self) -> bool {
+        self.over_limit.is_cancelled()
+    }
+
+    /// Cancelled together with the download once `limit` is passed
+    pub fn limit_token(&self) -> &CancellationToken {
+        &self.over_limit
+    }
+
+    pub fn get(&self) -> (String, u64, u64) {
+        (
+            self.name.lock().unwrap().clone(),
+            self.downloaded.load(Ordering::SeqCst),
+            self.total.load(Ordering::SeqCst),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_file_names() {
+        let cases = [
+            ("movie.mkv", Some("movie.mkv")),
+            ("  movie.mkv ", Some("movie.mkv")),
+            ("../../etc/passwd", Some("passwd")),
+            ("/etc/cron.d/job", Some("job")),
+            ("..\\..\\boot.ini", Some("boot.ini")),
+            ("..", None),
+            (".", None),
+            ("dir/", None),
+            ("", None),
+            ("a\0b", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(plain_file_name(name).as_deref(), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn paths_in_directory() {
+        let cases = [
+            ("./downloads", "movie.mkv", Some("./downloads/movie.mkv")),
+            ("downloads/", "movie.mkv", Some("downloads/movie.mkv")),
+            ("/data/123", "../../etc/x", Some("/data/123/x")),
+            ("/data/123", "/abs", Some("/data/123/abs")),
+            ("/data/123", "..", None),
+            ("/data/123", "sub/", None),
+        ];
+        for (directory, name, expected) in cases {
+            assert_eq!(path_in(directory, name).as_deref(), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn transferred_over_files() {
+        let progress = SharedProgress::default();
+        progress.set("a", 100, 300);
+        progress.set("a", 300, 300);
+        // Next file of a folder, or audio after video
+        progress.set("b", 50, 200);
+        progress.set("b", 200, 200);
+        progress.set("c", 10, 10);
+        assert_eq!(progress.transferred(), 510);
+        assert_eq!(progress.get(), ("c".to_string(), 10, 10));
+    }
+
+    #[test]
+    fn resumed_bytes_not_transferred() {
+        let progress = SharedProgress::default();
+        progress.set("a", 0, 1000);
+        progress.resume_at(600);
+        progress.set("a", 700, 1000);
+        assert_eq!(progress.transferred(), 100);
+    }
+
+    #[test]
+    fn stops_over_limit() {
+        let progress = SharedProgress::default();
+        let cancel_token = progress.limit_token().child_token();
+        progress.set_limit(Some(250));
+        progress.set("a", 200, 0);
+        assert!(!progress.is_over_limit());
+        progress.set("b", 100, 0);
+        assert!(progress.is_over_limit());
+        assert!(cancel_token.is_cancelled());
+    }
+}
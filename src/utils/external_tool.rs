@@ -0,0 +1,187 @@
+use super::bot_error::BotError;
+use super::custom_result::ResultGram;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+/// Only the end of the output is kept, progress lines can be endless
+const MAX_OUTPUT_SIZE: usize = 16 * 1024;
+
+/// Check if `program` can be found in PATH
+pub fn is_installed(program: &str) -> bool {
+    let paths = match std::env::var_os("PATH") {
+        Some(paths) => paths,
+        None => return false,
+    };
+    std::env::split_paths(&paths).any(|directory| {
+        let path = directory.join(program);
+        path.is_file() || (cfg!(windows) && path.with_extension("exe").is_file())
+    })
+}
+
+/// Run `command` as a child process without blocking the runtime
+/// Every line from stdout and stderr is passed to `on_line`, the process is killed on cancel or timeout
+/// Returns the end of stdout
+pub async fn run_tool<F>(
+    mut command: Command,
+    timeout: Duration,
+    cancel_token: &CancellationToken,
+    on_line: F,
+) -> ResultGram<String>
+where
+    F: Fn(&str) + Sync,
+{
+    let program = command.as_std().get_program().to_string_lossy().to_string();
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let mut child = command.spawn()?;
+    let stdout = child.stdout.take().ok_or("Failed to read stdout")?;
+    let stderr = child.stderr.take().ok_or("Failed to read stderr")?;
+
+    // Returning early drops the child, which kills the process
+    let run = async {
+        let (stdout, stderr) =
+            tokio::join!(read_lines(stdout, &on_line), read_lines(stderr, &on_line));
+        let status = child.wait().await?;
+        Ok::<_, std::io::Error>((status, stdout?, stderr?))
+    };
+    let (status, stdout, stderr) = tokio::select! {
+        result = run => result?,
+        _ = cancel_token.cancelled() => return Err(BotError::Cancelled.into()),
+        _ = tokio::time::sleep(timeout) => {
+            return Err(BotError::Timeout { what: program.to_string(), after: timeout }.into());
+        }
+    };
+
+    if !status.success() {
+        let reason = stderr
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or("no output");
+        return Err(BotError::ExternalTool {
+            program: program.to_string(),
+            detail: format!("({status}) {reason}"),
+        }
+        .into());
+    }
+    Ok(stdout)
+}
+
+/// Tools redraw progress with `\r`, so both `\r` and `\n` end a line
+async fn read_lines<R, F>(mut reader: R, on_line: &F) -> std::io::Result<String>
+where
+    R: AsyncRead + Unpin,
+    F: Fn(&str),
+{
+    let mut output = String::new();
+    let mut line: Vec<u8> = Vec::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        for byte in &buffer[..read] {
+            if *byte == b'\n' || *byte == b'\r' {
+                push_line(&mut line, &mut output, on_line);
+            } else {
+                line.push(*byte);
+            }
+        }
+    }
+    push_line(&mut line, &mut output, on_line);
+    Ok(output)
+}
+
+fn push_line<F: Fn(&str)>(line: &mut Vec<u8>, output: &mut String, on_line: &F) {
+    if line.is_empty() {
+        return;
+    }
+    let text = String::from_utf8_lossy(line).to_string();
+    line.clear();
+    on_line(&text);
+
+    output.push_str(&text);
+    output.push('\n');
+    if output.len() > MAX_OUTPUT_SIZE {
+        let mut cut = output.len() - MAX_OUTPUT_SIZE;
+        while !output.is_char_boundary(cut) {
+            cut += 1;
+        }
+        output.drain(..cut);
+    }
+}
+
+/// Parse tqdm progress like ` 45%|████▌     | 10.5M/23.3M [00:02<00:03, 4.12MB/s]`
+pub fn parse_tqdm_progress(line: &str) -> Option<(u64, u64)> {
+    let sizes = line.split('|').nth(2)?.split_whitespace().next()?;
+    let (downloaded, total) = sizes.split_once('/')?;
+    Some((parse_size(downloaded)?, parse_size(total)?))
+}
+
+/// Parse sizes like `300B`, `512k`, `10.5M` or `1.20GiB`
+/// `k`, `M`, `G` are 1000-based as tqdm prints them, `KiB`, `MiB`, `GiB` are 1024-based
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim().trim_start_matches('~');
+    let number_end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let number: f64 = value[..number_end].parse().ok()?;
+
+    let unit = value[number_end..].trim().to_ascii_uppercase();
+    let unit = unit.trim_end_matches('B');
+    let (unit, base) = match unit.strip_suffix('I') {
+        Some(unit) => (unit, 1024.0_f64),
+        None => (unit, 1000.0_f64),
+    };
+    let exponent = match unit {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        _ => return None,
+    };
+    Some((number * base.powi(exponent)) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        let cases = [
+            ("300", Some(300)),
+            ("300B", Some(300)),
+            ("512k", Some(512_000)),
+            ("10.5M", Some(10_500_000)),
+            ("2G", Some(2_000_000_000)),
+            ("1T", Some(1_000_000_000_000)),
+            ("1KiB", Some(1024)),
+            ("1.5MiB", Some(1_572_864)),
+            ("2GiB", Some(2 * 1024 * 1024 * 1024)),
+            ("~1.00GiB", Some(1024 * 1024 * 1024)),
+            ("2 GB", Some(2_000_000_000)),
+            ("5X", None),
+            ("abc", None),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(parse_size(value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn tqdm_progress() {
+        let line = " 45%|████▌     | 10.5M/23.3M [00:02<00:03, 4.12MB/s]";
+        assert_eq!(parse_tqdm_progress(line), Some((10_500_000, 23_300_000)));
+        assert_eq!(parse_tqdm_progress("Downloading..."), None);
+    }
+}
@@ -0,0 +1,724 @@
+use super::bot_error::BotError;
+use super::custom_result::ResultGram;
+use super::external_tool::{parse_tqdm_progress, run_tool};
+use super::url_policy::UrlPolicy;
+use reqwest::{header, Response};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::{fs, io::AsyncWriteExt, process::Command};
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+const DRIVE_URL: &str = "https://drive.google.com";
+pub const GDOWN_PROGRAM: &str = "gdown";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriveLink {
+    File(String),
+    Folder(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct DriveEntry {
+    pub id: String,
+    pub name: String,
+    pub is_folder: bool,
+}
+
+/// Parse Google Drive share links, or a bare file id
+pub fn parse_drive_link(input: &str) -> Option<DriveLink> {
+    let input = input.trim();
+    let url = match Url::parse(input) {
+        Ok(url) => url,
+        Err(_) => return is_drive_id(input).then(|| DriveLink::File(input.to_string())),
+    };
+
+    let host = url.host_str()?;
+    if host != "google.com" && !host.ends_with(".google.com") {
+        return None;
+    }
+
+    // `/file/d/<id>/view`, `/drive/folders/<id>`, `/drive/u/0/folders/<id>`
+    let segments: Vec<&str> = url.path_segments()?.collect();
+    for pair in segments.windows(2) {
+        if !is_drive_id(pair[1]) {
+            continue;
+        }
+        match pair[0] {
+            "d" => return Some(DriveLink::File(pair[1].to_string())),
+            "folders" => return Some(DriveLink::Folder(pair[1].to_string())),
+            _ => {}
+        }
+    }
+
+    // `/open?id=<id>`, `/uc?id=<id>`, `/embeddedfolderview?id=<id>`
+    let id = url
+        .query_pairs()
+        .find(|(key, _)| key == "id")
+        .map(|(_, value)| value.to_string())?;
+    if !is_drive_id(&id) {
+        return None;
+    }
+    if segments.contains(&"embeddedfolderview") {
+        return Some(DriveLink::Folder(id));
+    }
+    Some(DriveLink::File(id))
+}
+
+/// Ids are passed to gdown, one starting with `-` would be read as an option
+fn is_drive_id(value: &str) -> bool {
+    value.len() >= 10
+        && !value.starts_with('-')
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Minimal Google Drive client for publicly shared files and folders
+/// Every request goes through `UrlPolicy`
+#[derive(Clone)]
+pub struct DriveClient {
+    base_url: Url,
+    policy: UrlPolicy,
+}
+
+impl DriveClient {
+    pub fn new(policy: UrlPolicy) -> Self {
+        DriveClient::with_base_url(Url::parse(DRIVE_URL).unwrap(), policy)
+    }
+
+    /// Use another server instead of Google Drive, e.g. a local stand-in that imitates it
+    pub fn with_base_url(base_url: Url, policy: UrlPolicy) -> Self {
+        DriveClient { base_url, policy }
+    }
+
+    /// Returns folder name and its entries
+    pub async fn list_folder(&self, folder_id: &str) -> ResultGram<(String, Vec<DriveEntry>)> {
+        let mut url = self.base_url.join("embeddedfolderview")?;
+        url.query_pairs_mut().append_pair("id", folder_id);
+
+        let response = self.policy.get(url).await?.error_for_status()?;
+        let html = response.text().await?;
+        let title = parse_folder_title(&html).unwrap_or_else(|| folder_id.to_string());
+        Ok((title, parse_folder_entries(&html)))
+    }
+
+    /// Request the file, going through the virus scan warning page if Drive shows one
+    pub async fn open_file(&self, file_id: &str) -> ResultGram<Response> {
+        let mut url = self.base_url.join("uc")?;
+        url.query_pairs_mut()
+            .append_pair("export", "download")
+            .append_pair("id", file_id);
+
+        let response = self.policy.get(url.clone()).await?.error_for_status()?;
+        if !is_html(&response) {
+            return Ok(response);
+        }
+
+        let page = response.text().await?;
+        let confirm_url = parse_confirm_url(&url, &page)
+            .ok_or("Google Drive refused the download, make sure the file is shared publicly")?;
+        log::debug!("Drive confirm url: {confirm_url}");
+
+        let response = self.policy.get(confirm_url).await?.error_for_status()?;
+        if is_html(&response) {
+            return Err("Google Drive returned a page instead of the file".into());
+        }
+        Ok(response)
+    }
+
+    /// Download a file into `directory`, uses the name from Drive if `file_name` is None
+    /// `on_progress` gets called with file name, downloaded and total bytes
+    pub async fn download_file<F>(
+        &self,
+        file_id: &str,
+        directory: &Path,
+        file_name: Option<&str>,
+        cancel_token: &CancellationToken,
+        on_progress: &F,
+    ) -> ResultGram<PathBuf>
+    where
+        F: Fn(&str, u64, u64) + Sync,
+    {
+        let response = self.open_file(file_id).await?;
+        let name = match file_name {
+            Some(name) => sanitize_file_name(name),
+            None => content_disposition_name(&response)
+                .map(|name| sanitize_file_name(&name))
+                .unwrap_or_else(|| file_id.to_string()),
+        };
+        let path = directory.join(&name);
+        log::info!("Drive download {file_id} to {}", path.display());
+
+        let result = write_file(response, &path, &name, cancel_token, on_progress)
+            .await
+            .map_err(BotError::from);
+        if result.is_err() {
+            let _ = fs::remove_file(&path).await;
+        }
+        result?;
+        Ok(path)
+    }
+
+    /// Download a folder with all sub folders into `directory/<folder name>`
+    pub async fn download_folder<F>(
+        &self,
+        folder_id: &str,
+        directory: &Path,
+        cancel_token: &CancellationToken,
+        on_progress: &F,
+    ) -> ResultGram<Vec<PathBuf>>
+    where
+        F: Fn(&str, u64, u64) + Sync,
+    {
+        let mut files: Vec<PathBuf> = Vec::new();
+        let mut pending: Vec<(String, PathBuf)> =
+            vec![(folder_id.to_string(), directory.to_path_buf())];
+        // Shortcuts can point back to a parent folder
+        let mut visited: HashSet<String> = HashSet::new();
+
+        while let Some((id, parent)) = pending.pop() {
+            if !visited.insert(id.clone()) {
+                log::debug!("Drive folder {id} already downloaded, skipping");
+                continue;
+            }
+            let (title, entries) = self.list_folder(&id).await?;
+            let folder_path = parent.join(sanitize_file_name(&title));
+            fs::create_dir_all(&folder_path).await?;
+
+            for entry in entries {
+                if cancel_token.is_cancelled() {
+                    return Err(BotError::Cancelled.into());
+                }
+                if entry.is_folder {
+                    pending.push((entry.id, folder_path.clone()));
+                    continue;
+                }
+                let path = self
+                    .download_file(
+                        &entry.id,
+                        &folder_path,
+                        Some(&entry.name),
+                        cancel_token,
+                        on_progress,
+                    )
+                    .await?;
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+}
+
+/// Download with the `gdown` tool, used when the Drive pages changed and the native client fails
+pub async fn download_with_gdown<F>(
+    link: &DriveLink,
+    directory: &Path,
+    timeout: Duration,
+    cancel_token: &CancellationToken,
+    on_progress: &F,
+) -> ResultGram<Vec<PathBuf>>
+where
+    F: Fn(&str, u64, u64) + Sync,
+{
+    let mut command = Command::new(GDOWN_PROGRAM);
+    let id = match link {
+        DriveLink::File(id) => id,
+        DriveLink::Folder(id) => {
+            command.arg("--folder");
+            id
+        }
+    };
+    // Trailing separator tells gdown to keep the original file names
+    command.arg("-O").arg(format!(
+        "{}{}",
+        directory.display(),
+        std::path::MAIN_SEPARATOR
+    ));
+    command.arg("--").arg(id);
+
+    // gdown prints `To: <path>` before each file
+    let files: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+    run_tool(command, timeout, cancel_token, |line: &str| {
+        if let Some(path) = line.trim().strip_prefix("To: ") {
+            files.lock().unwrap().push(PathBuf::from(path));
+        } else if let Some((downloaded, total)) = parse_tqdm_progress(line) {
+            let name = match files.lock().unwrap().last() {
+                Some(path) => path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+                None => GDOWN_PROGRAM.to_string(),
+            };
+            on_progress(&name, downloaded, total);
+        }
+    })
+    .await?;
+
+    let files = files.into_inner().unwrap();
+    if files.is_empty() {
+        return Ok(vec![directory.to_path_buf()]);
+    }
+    Ok(files)
+}
+
+async fn write_file<F>(
+    mut response: Response,
+    path: &Path,
+    name: &str,
+    cancel_token: &CancellationToken,
+    on_progress: &F,
+) -> ResultGram<()>
+where
+    F: Fn(&str, u64, u64) + Sync,
+{
+    let total_size = response.content_length().unwrap_or(0);
+    let mut file = fs::File::create(path).await?;
+    let mut downloaded: u64 = 0;
+
+    while let Some(chunk) = response.chunk().await? {
+        if cancel_token.is_cancelled() {
+            return Err(BotError::Cancelled.into());
+        }
+        file.write_all(&chunk).await?;
+        downloaded += chunk.len() as u64;
+        on_progress(name, downloaded, total_size);
+    }
+    file.flush().await?;
+    Ok(())
+}
+
+fn is_html(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("text/html"))
+        .unwrap_or(false)
+}
+
+/// Find the confirmed download link on the virus scan warning page
+/// Newer pages use a form with hidden inputs, older ones link to `/uc?export=download&confirm=..`
+fn parse_confirm_url(page_url: &Url, html: &str) -> Option<Url> {
+    if let Some(form_id) = html.find("id=\"download-form\"") {
+        let form_start = html[..form_id].rfind("<form")?;
+        let form_end = form_id + html[form_id..].find("</form>")?;
+        let form = &html[form_start..form_end];
+
+        let action = attribute(form, "action")?;
+        let mut url = page_url.join(&decode_html(action)).ok()?;
+        {
+            let mut query = url.query_pairs_mut();
+            query.clear();
+            for input in form.split("<input").skip(1) {
+                let tag = &input[..input.find('>').unwrap_or(input.len())];
+                if let (Some(name), Some(value)) = (attribute(tag, "name"), attribute(tag, "value"))
+                {
+                    query.append_pair(&decode_html(name), &decode_html(value));
+                }
+            }
+        }
+        return Some(url);
+    }
+
+    let link_start = html.find("href=\"/uc?export=download")?;
+    let href = attribute(&html[link_start.saturating_sub(1)..], "href")?;
+    page_url.join(&decode_html(href)).ok()
+}
+
+fn parse_folder_title(html: &str) -> Option<String> {
+    let start = html.find("<title>")? + "<title>".len();
+    let end = start + html[start..].find("</title>")?;
+    let title = decode_html(html[start..end].trim());
+    if title.is_empty() {
+        return None;
+    }
+    Some(title)
+}
+
+/// Entries of `embeddedfolderview` page
+fn parse_folder_entries(html: &str) -> Vec<DriveEntry> {
+    let mut entries: Vec<DriveEntry> = Vec::new();
+    for entry in html.split("class=\"flip-entry\"").skip(1) {
+        let id = match between(entry, "id=\"entry-", "\"") {
+            Some(id) => id.to_string(),
+            None => continue,
+        };
+        let name = match between(entry, "class=\"flip-entry-title\">", "</div>") {
+            Some(name) => decode_html(name.trim()),
+            None => id.clone(),
+        };
+        entries.push(DriveEntry {
+            id,
+            name,
+            is_folder: entry.contains("/drive/folders/"),
+        });
+    }
+    entries
+}
+
+/// File name from `Content-Disposition`, prefers the utf-8 `filename*` form
+fn content_disposition_name(response: &Response) -> Option<String> {
+    let value = response
+        .headers()
+        .get(header::CONTENT_DISPOSITION)?
+        .to_str()
+        .ok()?;
+
+    if let Some(start) = value.find("filename*=") {
+        let encoded = value[start + "filename*=".len()..]
+            .split(';')
+            .next()?
+            .trim()
+            .trim_matches('"');
+        let encoded = match encoded.find("''") {
+            Some(index) => &encoded[index + 2..],
+            None => encoded,
+        };
+        return Some(percent_decode(encoded));
+    }
+
+    let start = value.find("filename=")? + "filename=".len();
+    let name = value[start..].split(';').next()?.trim().trim_matches('"');
+    if name.is_empty() {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Value of `name="value"` inside a tag
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    between(tag, &format!(" {name}=\""), "\"")
+}
+
+fn between<'a>(text: &'a str, start: &str, end: &str) -> Option<&'a str> {
+    let from = text.find(start)? + start.len();
+    let to = from + text[from..].find(end)?;
+    Some(&text[from..to])
+}
+
+fn decode_html(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(hex, 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Names coming from Drive must not escape the download directory
+fn sanitize_file_name(name: &str) -> String {
+    let name = name.replace(['/', '\\'], "_");
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        return "download".to_string();
+    }
+    name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_server::{serve, Reply};
+
+    const FILE_ID: &str = "1AbCdEfGhIjK_file";
+    const ROOT_ID: &str = "1AbCdEfGhIjK_root";
+    const CHILD_ID: &str = "1AbCdEfGhIjK_child";
+
+    fn client(base_url: Url) -> DriveClient {
+        DriveClient::with_base_url(base_url, UrlPolicy::new(&["127.0.0.1".to_string()]))
+    }
+
+    fn query(url: &Url, key: &str) -> Option<String> {
+        url.query_pairs()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.to_string())
+    }
+
+    fn folder_page(title: &str, entries: &[(&str, &str, bool)]) -> String {
+        let mut html = format!("<html><head><title>{title}</title></head><body>");
+        for (id, name, is_folder) in entries {
+            let link = if *is_folder {
+                format!("https://drive.google.com/drive/folders/{id}")
+            } else {
+                format!("https://drive.google.com/file/d/{id}/view")
+            };
+            html.push_str(&format!(
+                "<div class=\"flip-entry\" id=\"entry-{id}\" tabindex=\"0\"><a href=\"{link}\">\
+                 <div class=\"flip-entry-title\">{name}</div></a></div>"
+            ));
+        }
+        html.push_str("</body></html>");
+        html
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gdrive-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn drive_links() {
+        let cases = [
+            (
+                "https://drive.google.com/file/d/1AbCdEfGhIjK/view?usp=sharing",
+                Some(DriveLink::File("1AbCdEfGhIjK".to_string())),
+            ),
+            (
+                "https://drive.google.com/drive/folders/1AbCdEfGhIjK",
+                Some(DriveLink::Folder("1AbCdEfGhIjK".to_string())),
+            ),
+            (
+                "https://drive.google.com/drive/u/0/folders/1AbCdEfGhIjK",
+                Some(DriveLink::Folder("1AbCdEfGhIjK".to_string())),
+            ),
+            (
+                "https://drive.google.com/open?id=1AbCdEfGhIjK",
+                Some(DriveLink::File("1AbCdEfGhIjK".to_string())),
+            ),
+            (
+                "https://drive.google.com/embeddedfolderview?id=1AbCdEfGhIjK",
+                Some(DriveLink::Folder("1AbCdEfGhIjK".to_string())),
+            ),
+            (
+                "  1AbCdEfGhIjK_-x  ",
+                Some(DriveLink::File("1AbCdEfGhIjK_-x".to_string())),
+            ),
+            ("https://example.com/file/d/1AbCdEfGhIjK/view", None),
+            (
+                "https://drive.google.com.evil.com/file/d/1AbCdEfGhIjK",
+                None,
+            ),
+            ("https://drive.google.com/file/d/short/view", None),
+            ("https://drive.google.com/open?id=1AbC/../x", None),
+            ("https://drive.google.com/open?id=-oProxyCommand", None),
+            ("-1AbCdEfGhIjK", None),
+            ("short", None),
+            ("not a drive id", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_drive_link(input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn drive_ids() {
+        let cases = [
+            ("1AbCdEfGhIjK", true),
+            ("1AbCdEfGhIjK-_", true),
+            ("-1AbCdEfGhIjK", false),
+            ("--version-x", false),
+            ("123456789", false),
+            ("1AbCdEfGh IjK", false),
+            ("1AbCdEfGh/IjK", false),
+            ("", false),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(is_drive_id(value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn confirm_url_of_old_page() {
+        let page_url = Url::parse("https://drive.google.com/uc?export=download&id=x").unwrap();
+        let html =
+            "<a id=\"uc-download-link\" href=\"/uc?export=download&amp;confirm=AbCd&amp;id=x\">";
+        let url = parse_confirm_url(&page_url, html).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://drive.google.com/uc?export=download&confirm=AbCd&id=x"
+        );
+        assert!(parse_confirm_url(&page_url, "<html>Sign in</html>").is_none());
+    }
+
+    #[tokio::test]
+    async fn large_file_confirm_page() {
+        let base_url = serve(|url: &Url, _: &[u8]| match url.path() {
+            "/uc" => Reply::html(format!(
+                "<html><body>Google Drive can't scan this file for viruses.\
+                 <form id=\"download-form\" action=\"/download\" method=\"get\">\
+                 <input type=\"hidden\" name=\"id\" value=\"{}\">\
+                 <input type=\"hidden\" name=\"confirm\" value=\"t\">\
+                 <input type=\"hidden\" name=\"uuid\" value=\"a&amp;b\">\
+                 </form></body></html>",
+                query(url, "id").unwrap()
+            )),
+            "/download"
+                if query(url, "confirm").as_deref() == Some("t")
+                    && query(url, "uuid").as_deref() == Some("a&b") =>
+            {
+                Reply::file(b"large file".to_vec())
+            }
+            _ => Reply::html("<html>Not found</html>"),
+        })
+        .await;
+
+        let directory = temp_dir("confirm");
+        let path = client(base_url)
+            .download_file(
+                FILE_ID,
+                &directory,
+                Some("big.bin"),
+                &CancellationToken::new(),
+                &|_: &str, _, _| {},
+            )
+            .await
+            .unwrap();
+        assert_eq!(path, directory.join("big.bin"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"large file");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refused_confirm_page() {
+        let base_url = serve(|_: &Url, _: &[u8]| Reply::html("<html>Sign in</html>")).await;
+        let error = client(base_url).open_file(FILE_ID).await.unwrap_err();
+        assert!(error.to_string().contains("shared publicly"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn folder_listing_with_sub_folders() {
+        // Each folder is listed on its own page, sub folders are fetched one after another
+        let base_url = serve(|url: &Url, _: &[u8]| {
+            let id = query(url, "id").unwrap_or_default();
+            match (url.path(), id.as_str()) {
+                ("/embeddedfolderview", ROOT_ID) => Reply::html(folder_page(
+                    "Root &amp; more",
+                    &[
+                        ("1AbCdEfGhIjK_a", "a.txt", false),
+                        (CHILD_ID, "Child", true),
+                        ("1AbCdEfGhIjK_b", "b/../b.txt", false),
+                    ],
+                )),
+                ("/embeddedfolderview", CHILD_ID) => {
+                    Reply::html(folder_page("Child", &[("1AbCdEfGhIjK_c", "c.txt", false)]))
+                }
+                ("/uc", file_id) => Reply::file(file_id.as_bytes().to_vec()),
+                _ => Reply::html("<html>Not found</html>"),
+            }
+        })
+        .await;
+        let client = client(base_url);
+
+        let (title, entries) = client.list_folder(ROOT_ID).await.unwrap();
+        assert_eq!(title, "Root & more");
+        let names: Vec<(&str, bool)> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.is_folder))
+            .collect();
+        assert_eq!(
+            names,
+            vec![("a.txt", false), ("Child", true), ("b/../b.txt", false)]
+        );
+
+        let directory = temp_dir("folder");
+        let mut files = client
+            .download_folder(
+                ROOT_ID,
+                &directory,
+                &CancellationToken::new(),
+                &|_: &str, _, _| {},
+            )
+            .await
+            .unwrap();
+        files.sort();
+        let root = directory.join("Root & more");
+        assert_eq!(
+            files,
+            vec![
+                root.join("Child").join("c.txt"),
+                root.join("a.txt"),
+                root.join("b_.._b.txt"),
+            ]
+        );
+        assert_eq!(
+            std::fs::read(root.join("a.txt")).unwrap(),
+            b"1AbCdEfGhIjK_a"
+        );
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn folder_shortcut_cycle() {
+        let base_url = serve(|url: &Url, _: &[u8]| {
+            let id = query(url, "id").unwrap_or_default();
+            match (url.path(), id.as_str()) {
+                ("/embeddedfolderview", ROOT_ID) => Reply::html(folder_page(
+                    "Root",
+                    &[
+                        (CHILD_ID, "Child", true),
+                        ("1AbCdEfGhIjK_a", "a.txt", false),
+                    ],
+                )),
+                // Shortcut back to the root folder
+                ("/embeddedfolderview", CHILD_ID) => {
+                    Reply::html(folder_page("Child", &[(ROOT_ID, "Root", true)]))
+                }
+                ("/uc", _) => Reply::file(b"a".to_vec()),
+                _ => Reply::html("<html>Not found</html>"),
+            }
+        })
+        .await;
+
+        let directory = temp_dir("cycle");
+        let files = client(base_url)
+            .download_folder(
+                ROOT_ID,
+                &directory,
+                &CancellationToken::new(),
+                &|_: &str, _, _| {},
+            )
+            .await
+            .unwrap();
+        assert_eq!(files, vec![directory.join("Root").join("a.txt")]);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancel_mid_download() {
+        let base_url = serve(|_: &Url, _: &[u8]| Reply::file(vec![0u8; 4 * 1024 * 1024])).await;
+
+        let directory = temp_dir("cancel");
+        let cancel_token = CancellationToken::new();
+        let error = client(base_url)
+            .download_file(
+                FILE_ID,
+                &directory,
+                Some("cancelled.bin"),
+                &cancel_token,
+                &|_: &str, _, _| cancel_token.cancel(),
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error.downcast_ref::<BotError>(), Some(BotError::Cancelled)),
+            "{error}"
+        );
+        assert!(!directory.join("cancelled.bin").exists());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
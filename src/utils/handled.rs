@@ -0,0 +1,51 @@
+use super::json_store::{self, JsonStore};
+use crate::app_config::AppConfig;
+use grammers_client::types::Message;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Latest messages remembered, catch up only repeats recent ones
+const MAX_HANDLED: usize = 1000;
+
+lazy_static::lazy_static! {
+    /// Chat and message ids of `HANDLED_FILE`, oldest first
+    static ref HANDLED: JsonStore<VecDeque<(i64, i32)>> = JsonStore::default();
+    /// Ids changed since the last `save`
+    static ref CHANGED: AtomicBool = AtomicBool::new(false);
+}
+
+/// True the first time `message` is seen, catch up and reconnects can deliver it again
+/// Only kept in memory, `save` writes it to `HANDLED_FILE`
+pub fn first_time(config: &AppConfig, message: &Message) -> bool {
+    let key = (message.chat().id(), message.id());
+    HANDLED.with(&config.handled_file, |handled| {
+        if handled.contains(&key) {
+            log::debug!("Skipping message {} of {}, already handled", key.1, key.0);
+            return false;
+        }
+        handled.push_back(key);
+        while handled.len() > MAX_HANDLED {
+            handled.pop_front();
+        }
+        CHANGED.store(true, Ordering::Relaxed);
+        true
+    })
+}
+
+/// Write the ids to `path` if they changed, off the async runtime
+pub async fn save(path: &str) {
+    if !CHANGED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let handled = HANDLED.with(path, |handled| handled.clone());
+    let path = path.to_string();
+    let result = tokio::task::spawn_blocking(move || {
+        json_store::save(&path, &handled).map_err(|e| format!("Failed to save {path}: {e}"))
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::error!("{e}"),
+        Err(e) => log::error!("Failed to save handled messages: {e}"),
+    }
+}
@@ -1,29 +1,60 @@
 use grammers_client::session::PackedType;
 use grammers_client::types::Media::Document;
-use grammers_client::types::{media, CallbackQuery, Message, PackedChat};
-use grammers_client::{button, reply_markup, Client, InputMessage, Update};
-use tokio::time::{timeout, Duration};
+use grammers_client::types::{media, CallbackQuery, Chat, Message, PackedChat};
+use grammers_client::{button, reply_markup, Client, InputMessage};
+use std::time::Duration;
 
 use crate::app_config::AppConfig;
+use crate::utils::conversation::{chosen_option, expect_message, expect_query};
+use crate::utils::download_utils::plain_file_name;
+use crate::utils::quota::allowed_directories;
+use crate::utils::user_store::get_user;
 
 use super::custom_result::ResultGram;
 
-pub async fn send_message_to_user(bot: Client, user_id: i64, message: &str) -> ResultGram<()> {
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Send a message to user by id
+pub async fn send_message_to_user<M: Into<InputMessage>>(
+    bot: Client,
+    user_id: i64,
+    message: M,
+) -> ResultGram<()> {
+    let message: InputMessage = message.into();
+    let chat = resolve_user(&bot, user_id).await?;
+    bot.send_message(&chat, message).await?;
+    Ok(())
+}
+
+/// Find user by id, uses the access hash stored when the user asked for access
+pub async fn resolve_user(bot: &Client, user_id: i64) -> ResultGram<Chat> {
+    let config = AppConfig::current();
+    let access_hash = get_user(&config.users_file, user_id)
+        .and_then(|user| user.access_hash)
+        .unwrap_or(0);
     let chat = bot
         .unpack_chat(PackedChat {
             ty: PackedType::User,
             id: user_id,
-            access_hash: Some(0),
+            access_hash: Some(access_hash),
         })
         .await?;
-    bot.send_message(&chat, message).await?;
-    Ok(())
+    Ok(chat)
 }
 
-/// Get Directory from user if there are more then one director in env
-pub async fn get_directory(bot: Client, message: Message) -> ResultGram<Option<String>> {
-    let config = AppConfig::from_env().unwrap();
-    let download_directories: Vec<String> = config.download_directory;
+/// Get Directory from user if the sender is allowed more then one directory
+pub async fn get_directory(message: Message) -> ResultGram<Option<String>> {
+    let config = AppConfig::current();
+    let user_id = message.sender().map(|sender| sender.id()).unwrap_or(0);
+    let download_directories: Vec<String> = allowed_directories(&config, user_id);
+
+    if download_directories.is_empty() {
+        message
+            .reply("You are not allowed to download to any directory")
+            .await?;
+        return Ok(None);
+    }
 
     if download_directories.len() == 1 {
         let dest: String = download_directories[0].clone();
@@ -32,7 +63,6 @@ pub async fn get_directory(bot: Client, message: Message) -> ResultGram<Option<S
     }
 
     let choosed_option = ask_query(
-        bot.clone(),
         message,
         "Choose a download directory:",
         download_directories.clone(),
@@ -43,20 +73,31 @@ pub async fn get_directory(bot: Client, message: Message) -> ResultGram<Option<S
         return Ok(None);
     }
 
+    // Data of a forged button can point past the list, that's a cancel
     let chosen_dir_index = choosed_option.unwrap();
-    let chosen_dir = download_directories[chosen_dir_index as usize].clone();
-    return Ok(Some(chosen_dir));
+    let chosen_dir = download_directories.get(chosen_dir_index as usize).cloned();
+    return Ok(chosen_dir);
 }
 
-pub async fn get_custom_file_name(bot: Client, message: Message) -> ResultGram<Option<String>> {
+pub async fn get_custom_file_name(message: Message) -> ResultGram<Option<String>> {
+    // Wait before asking, the answer can't slip past us
+    let expected = expect_message(message.chat().id(), sender_id(&message));
     let file_name_message = message.reply("Send File Name").await?;
-    let response: Message = match get_next_message(bot.clone(), message.chat().id(), 60).await {
+    let response: Message = match expected.wait(REPLY_TIMEOUT).await {
         Some(mesage) => mesage,
         None => return Ok(None),
     };
     response.delete().await?;
     file_name_message.delete().await?;
-    return Ok(Some(response.text().to_string()));
+
+    // Only a name, a path could point outside the download directory
+    match plain_file_name(response.text()) {
+        Some(name) => Ok(Some(name)),
+        None => {
+            message.reply("Invalid file name").await?;
+            Ok(None)
+        }
+    }
 }
 
 /// Get only Document from the Message
@@ -72,89 +113,41 @@ pub fn get_document(message: Message) -> Option<media::Document> {
 
 // Ask for options, and get back result
 pub async fn ask_query(
-    bot: Client,
     message: Message,
     title: &str,
     options: Vec<String>,
-) -> ResultGram<Option<u8>> {
+) -> ResultGram<Option<u16>> {
+    if options.len() > u16::MAX as usize + 1 {
+        return Err(format!("Too many options to choose from: {}", options.len()).into());
+    }
+    let expected = expect_query(message.chat().id(), sender_id(&message));
     let mut buttons: Vec<Vec<button::Inline>> = Vec::new();
 
     for (index, option) in options.iter().enumerate() {
-        buttons.push(vec![button::inline(option, [index as u8])]);
+        buttons.push(vec![button::inline(
+            option,
+            expected.button_data(index as u16),
+        )]);
     }
 
     let message_reply = message
         .reply(InputMessage::text(title).reply_markup(&reply_markup::inline(buttons)))
         .await?;
 
-    let query_result = get_callback_query_response(bot.clone(), message.chat().id(), 30).await;
+    let query_result = expected.wait(QUERY_TIMEOUT).await;
     if query_result.is_none() {
         message_reply.edit("Timeout, please try again").await?;
         return Ok(None);
     }
 
     let query: CallbackQuery = query_result.unwrap();
-    let choosen_option = query.data()[0];
+    let choosen_option = chosen_option(&query);
     query.answer().send().await?;
     message_reply.delete().await?;
-    return Ok(Some(choosen_option));
+    return Ok(choosen_option);
 }
 
-// Wait for Query Response
-pub async fn get_callback_query_response(
-    bot: Client,
-    chat_id: i64,
-    timeout_seconds: u64,
-) -> Option<CallbackQuery> {
-    let client_hadle = bot.clone();
-    if let Ok(result) = timeout(Duration::from_secs(timeout_seconds), async {
-        loop {
-            if let Ok(update) = client_hadle.next_update().await {
-                let query = match update {
-                    Update::CallbackQuery(message) => Some(message),
-                    _ => None,
-                };
-                if query.is_some() {
-                    let query_message = query.unwrap().clone();
-                    if query_message.chat().id() == chat_id {
-                        break Some(query_message);
-                    }
-                }
-            }
-        }
-    })
-    .await
-    {
-        result
-    } else {
-        None
-    }
-}
-
-pub async fn get_next_message(bot: Client, chat_id: i64, timeout_seconds: u64) -> Option<Message> {
-    let client_hadle = bot.clone();
-    if let Ok(result) = timeout(Duration::from_secs(timeout_seconds), async {
-        loop {
-            if let Ok(update) = client_hadle.next_update().await {
-                let message: Option<Message> = match update {
-                    Update::NewMessage(message) => match message.outgoing() {
-                        true => None,
-                        false => Some(message),
-                    },
-                    _ => None,
-                };
-                if let Some(message_data) = message {
-                    if message_data.chat().id() == chat_id {
-                        break Some(message_data);
-                    }
-                }
-            }
-        }
-    })
-    .await
-    {
-        result
-    } else {
-        None
-    }
+/// Only the user who started the conversation can answer it
+fn sender_id(message: &Message) -> Option<i64> {
+    message.sender().map(|sender| sender.id())
 }
@@ -0,0 +1,195 @@
+use super::audit::format_age;
+use super::custom_result::ResultGram;
+use super::json_store::{self, now, JsonStore};
+use crate::app_config::AppConfig;
+use grammers_client::types::Message;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+lazy_static::lazy_static! {
+    /// Entries of `HISTORY_FILE`, oldest first
+    static ref HISTORY: JsonStore<Vec<HistoryEntry>> = JsonStore::default();
+}
+
+/// Completed download
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub sha256: Option<String>,
+    pub time: u64,
+    /// Seconds
+    pub duration: u64,
+    /// File was deleted with the Delete button
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+impl HistoryEntry {
+    /// e.g. `movie.mkv (700.0 MB, 3h ago)`
+    pub fn summary(&self) -> String {
+        format!(
+            "{} ({:.1} MB, {} ago){}",
+            self.name,
+            self.size as f64 / (1024.0 * 1024.0),
+            format_age(now().saturating_sub(self.time)),
+            if self.deleted { " deleted" } else { "" }
+        )
+    }
+
+    pub fn details(&self) -> String {
+        format!(
+            "{}\nPath: {}\nSize: {:.1} MB\nSHA-256: {}\nDownloaded in {} sec by {}\nFrom chat {} message {}{}",
+            self.name,
+            self.path,
+            self.size as f64 / (1024.0 * 1024.0),
+            self.sha256.as_deref().unwrap_or("unknown"),
+            self.duration,
+            self.user_id,
+            self.chat_id,
+            self.message_id,
+            if self.deleted { "\nFile was deleted" } else { "" }
+        )
+    }
+}
+
+/// Store a completed download sent with `message`, errors are only logged
+/// Hashing can take a while for big files
+pub async fn record_download(
+    config: &AppConfig,
+    message: &Message,
+    path: &Path,
+    duration: Duration,
+) {
+    let user_id = message.sender().map(|sender| sender.id()).unwrap_or(0);
+    let chat_id = message.chat().id();
+    let message_id = message.id();
+
+    let hash_path = path.to_path_buf();
+    let sha256 = match tokio::task::spawn_blocking(move || file_hash(&hash_path)).await {
+        Ok(Ok(hash)) => Some(hash),
+        Ok(Err(e)) => {
+            log::warn!("Failed to hash {}: {e}", path.display());
+            None
+        }
+        Err(e) => {
+            log::warn!("Failed to hash {}: {e}", path.display());
+            None
+        }
+    };
+
+    let entry = HistoryEntry {
+        id: 0,
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        path: path.to_string_lossy().to_string(),
+        size: std::fs::metadata(path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0),
+        user_id,
+        chat_id,
+        message_id,
+        sha256,
+        time: now(),
+        duration: duration.as_secs(),
+        deleted: false,
+    };
+    if let Err(e) = add_entry(config, entry) {
+        log::error!("Failed to record {} in history: {e}", path.display());
+    }
+}
+
+fn add_entry(config: &AppConfig, mut entry: HistoryEntry) -> ResultGram<()> {
+    HISTORY.with(&config.history_file, |entries| {
+        entry.id = entries.last().map(|last| last.id + 1).unwrap_or(1);
+        entries.push(entry);
+        json_store::save(&config.history_file, entries)
+    })
+}
+
+/// Newest first, `None` as `user_id` for everyone's downloads
+/// Returns the page and the number of matching entries
+pub fn recent(
+    config: &AppConfig,
+    user_id: Option<i64>,
+    page: usize,
+    page_size: usize,
+) -> (Vec<HistoryEntry>, usize) {
+    HISTORY.with(&config.history_file, |entries| {
+        let visible: Vec<&HistoryEntry> = entries
+            .iter()
+            .rev()
+            .filter(|entry| user_id.is_none() || user_id == Some(entry.user_id))
+            .collect();
+        let page_entries = visible
+            .iter()
+            .skip(page * page_size)
+            .take(page_size)
+            .map(|entry| (*entry).clone())
+            .collect();
+        (page_entries, visible.len())
+    })
+}
+
+/// Newest first, file names containing `text`, ignoring case
+pub fn find(
+    config: &AppConfig,
+    user_id: Option<i64>,
+    text: &str,
+    limit: usize,
+) -> Vec<HistoryEntry> {
+    let text = text.to_lowercase();
+    HISTORY.with(&config.history_file, |entries| {
+        entries
+            .iter()
+            .rev()
+            .filter(|entry| user_id.is_none() || user_id == Some(entry.user_id))
+            .filter(|entry| entry.name.to_lowercase().contains(&text))
+            .take(limit)
+            .cloned()
+            .collect()
+    })
+}
+
+pub fn get(config: &AppConfig, id: u64) -> Option<HistoryEntry> {
+    HISTORY.with(&config.history_file, |entries| {
+        entries.iter().find(|entry| entry.id == id).cloned()
+    })
+}
+
+pub fn mark_deleted(config: &AppConfig, id: u64) -> ResultGram<()> {
+    HISTORY.with(&config.history_file, |entries| {
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.id == id) {
+            entry.deleted = true;
+        }
+        json_store::save(&config.history_file, entries)
+    })
+}
+
+fn file_hash(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
@@ -0,0 +1,38 @@
+use super::external_tool::run_tool;
+use super::history::record_download;
+use crate::app_config::AppConfig;
+use grammers_client::types::Message;
+use std::path::Path;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+/// Run after every finished download, records it in history and starts the `on_download` hook
+pub async fn download_finished(
+    config: &AppConfig,
+    message: &Message,
+    path: &Path,
+    duration: Duration,
+) {
+    record_download(config, message, path, duration).await;
+
+    let hook = match &config.on_download_hook {
+        Some(hook) => hook.clone(),
+        None => return,
+    };
+    // The hook runs in the background, a slow script shouldn't hold the reply
+    let mut command = Command::new(&hook);
+    command.arg(path);
+    let timeout = Duration::from_secs(config.external_tool_timeout);
+    let path = path.display().to_string();
+    tokio::spawn(async move {
+        let on_line = |line: &str| log::debug!("{hook}: {line}");
+        let result = run_tool(command, timeout, &CancellationToken::new(), on_line)
+            .await
+            .map_err(|e| e.to_string());
+        match result {
+            Ok(_) => log::info!("Hook {hook} done for {path}"),
+            Err(error) => log::error!("Hook {hook} failed for {path}: {error}"),
+        }
+    });
+}
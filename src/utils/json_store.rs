@@ -0,0 +1,95 @@
+use super::custom_result::ResultGram;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Content of a json file kept in memory, loaded on first use
+/// Loaded again if another path is used, like after a config reload
+pub struct JsonStore<T> {
+    loaded: Mutex<Option<(String, T)>>,
+    load: fn(&str) -> T,
+}
+
+impl<T: DeserializeOwned + Default> Default for JsonStore<T> {
+    fn default() -> Self {
+        JsonStore::with_loader(load)
+    }
+}
+
+impl<T> JsonStore<T> {
+    /// For files whose layout differs from the content kept in memory
+    pub fn with_loader(load: fn(&str) -> T) -> Self {
+        JsonStore {
+            loaded: Mutex::new(None),
+            load,
+        }
+    }
+
+    /// Run `f` on the content of `path`
+    pub fn with<R>(&self, path: &str, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut loaded = self.loaded.lock().unwrap();
+        if loaded.as_ref().map(|(loaded, _)| loaded.as_str()) != Some(path) {
+            *loaded = Some((path.to_string(), (self.load)(path)));
+        }
+        f(&mut loaded.as_mut().unwrap().1)
+    }
+}
+
+/// Content of `path`, default if it doesn't exist or can't be parsed
+pub fn load<T: DeserializeOwned + Default>(path: &str) -> T {
+    match std::fs::read(path) {
+        Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|e| {
+            log::error!("Failed to read {path}: {e}");
+            T::default()
+        }),
+        Err(_) => T::default(),
+    }
+}
+
+/// Write `value` to `path`
+pub fn save<T: Serialize + ?Sized>(path: &str, value: &T) -> ResultGram<()> {
+    // Write next to the file first, so a crash can't leave it half written
+    let temp_path = format!("{path}.tmp");
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+/// Seconds since epoch, the time stored in every file
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn saved_content_is_loaded_again() {
+        let path = std::env::temp_dir().join(format!("store-{}.json", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let missing: HashMap<String, u64> = load(&path);
+        assert!(missing.is_empty());
+
+        let store: JsonStore<HashMap<String, u64>> = JsonStore::default();
+        store
+            .with(&path, |content| {
+                content.insert("movie.mkv".to_string(), 700);
+                save(&path, content)
+            })
+            .unwrap();
+        assert!(!std::path::Path::new(&format!("{path}.tmp")).exists());
+        let loaded: HashMap<String, u64> = load(&path);
+        assert_eq!(loaded.get("movie.mkv"), Some(&700));
+
+        std::fs::write(&path, "not json").unwrap();
+        let broken: HashMap<String, u64> = load(&path);
+        assert!(broken.is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use super::custom_result::ResultGram;
+use super::progress::{ProgressEvent, ProgressUpdate};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+#[derive(Default)]
+struct Metrics {
+    /// Running downloads by id
+    running: BTreeMap<u64, Running>,
+    finished: u64,
+    failed: u64,
+    interrupted: u64,
+    downloaded_bytes: u64,
+}
+
+/// Metric name and how to read it from a running download
+type Gauge = (&'static str, fn(&Running) -> f64);
+
+struct Running {
+    name: String,
+    downloaded: u64,
+    total: u64,
+    speed: f64,
+    paused: bool,
+}
+
+/// Update the metrics with an event of a download
+pub fn record(event: &ProgressEvent, update: &ProgressUpdate) {
+    let mut metrics = METRICS.lock().unwrap();
+    match event {
+        ProgressEvent::Started | ProgressEvent::Bytes | ProgressEvent::Paused => {
+            metrics.running.insert(
+                update.id,
+                Running {
+                    name: update.name.clone(),
+                    downloaded: update.downloaded,
+                    total: update.total,
+                    speed: update.speed,
+                    paused: matches!(event, ProgressEvent::Paused),
+                },
+            );
+        }
+        ProgressEvent::Finished => {
+            metrics.running.remove(&update.id);
+            metrics.finished += 1;
+            metrics.downloaded_bytes += update.downloaded;
+        }
+        ProgressEvent::Failed(_) => {
+            metrics.running.remove(&update.id);
+            metrics.failed += 1;
+        }
+        ProgressEvent::Interrupted => {
+            metrics.running.remove(&update.id);
+            metrics.interrupted += 1;
+        }
+    }
+}
+
+/// Metrics in the Prometheus text format
+pub fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut lines = vec![
+        "# TYPE telegram_bot_downloads_finished_total counter".to_string(),
+        format!("telegram_bot_downloads_finished_total {}", metrics.finished),
+        "# TYPE telegram_bot_downloads_failed_total counter".to_string(),
+        format!("telegram_bot_downloads_failed_total {}", metrics.failed),
+        "# TYPE telegram_bot_downloads_interrupted_total counter".to_string(),
+        format!(
+            "telegram_bot_downloads_interrupted_total {}",
+            metrics.interrupted
+        ),
+        "# TYPE telegram_bot_downloaded_bytes_total counter".to_string(),
+        format!(
+            "telegram_bot_downloaded_bytes_total {}",
+            metrics.downloaded_bytes
+        ),
+        "# TYPE telegram_bot_downloads_running gauge".to_string(),
+        format!("telegram_bot_downloads_running {}", metrics.running.len()),
+    ];
+
+    let gauges: [Gauge; 4] = [
+        ("download_bytes", |running| running.downloaded as f64),
+        ("download_size_bytes", |running| running.total as f64),
+        ("download_speed_bytes", |running| running.speed),
+        ("download_paused", |running| running.paused as u8 as f64),
+    ];
+    for (name, value) in gauges {
+        lines.push(format!("# TYPE telegram_bot_{name} gauge"));
+        for (id, running) in &metrics.running {
+            lines.push(format!(
+                "telegram_bot_{name}{{id=\"{id}\",name=\"{}\"}} {}",
+                escape_label(&running.name),
+                value(running)
+            ));
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve the metrics over plain HTTP on `address`, every path gets the same answer
+pub async fn serve(address: &str) -> ResultGram<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind(address).await?;
+    log::info!("Serving metrics on http://{address}/metrics");
+
+    tokio::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!("Metrics listener failed: {e}");
+                    return;
+                }
+            };
+            tokio::spawn(async move {
+                // The request is not parsed, any path gets the metrics
+                let mut request = [0u8; 1024];
+                if let Err(e) = stream.read(&mut request).await {
+                    log::warn!("Bad metrics request: {e}");
+                    return;
+                }
+                let body = render();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                if let Err(e) = stream.write_all(response.as_bytes()).await {
+                    log::warn!("Failed to answer metrics request: {e}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
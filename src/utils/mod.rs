@@ -1,3 +1,28 @@
+pub mod access_control;
+pub mod aria2;
+pub mod audit;
+pub mod bot_error;
+pub mod callback_data;
+pub mod control;
+pub mod conversation;
 pub mod custom_result;
+pub mod download_registry;
 pub mod download_utils;
+pub mod external_tool;
+pub mod gdrive;
+pub mod handled;
 pub mod helper;
+pub mod history;
+pub mod hooks;
+pub mod json_store;
+pub mod metrics;
+pub mod pending;
+pub mod progress;
+pub mod quota;
+pub mod self_check;
+pub mod shutdown;
+#[cfg(test)]
+pub mod test_server;
+pub mod url_policy;
+pub mod user_store;
+pub mod ytdlp;
@@ -0,0 +1,78 @@
+use super::custom_result::ResultGram;
+use super::json_store;
+use super::ytdlp::VideoQuality;
+use crate::app_config::AppConfig;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use grammers_client::types::{Message, PackedChat};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    /// Interrupted downloads save at the same time during shutdown
+    static ref PENDING_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Download interrupted by shutdown, continued after restart
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PendingJob {
+    /// Packed chat of the message, base64
+    pub chat: String,
+    pub message_id: i32,
+    pub user_id: i64,
+    pub name: String,
+    pub path: String,
+    pub kind: PendingKind,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PendingKind {
+    /// Telegram file, bytes before `offset` are already written
+    Document { offset: u64 },
+    /// Direct link, downloaded again from the start
+    Url { url: String },
+    /// aria2 keeps downloading, `completed` bytes were already counted
+    Aria2 { gid: String, completed: u64 },
+    /// Google Drive file or folder, downloaded again into the directory in `path`
+    Drive { link: String },
+    /// yt-dlp continues its partial files in the directory in `path`
+    Video { url: String, quality: VideoQuality },
+}
+
+impl PendingJob {
+    pub fn new(message: &Message, name: &str, path: &str, kind: PendingKind) -> Self {
+        PendingJob {
+            chat: STANDARD.encode(message.chat().pack().to_bytes()),
+            message_id: message.id(),
+            user_id: message.sender().map(|sender| sender.id()).unwrap_or(0),
+            name: name.to_string(),
+            path: path.to_string(),
+            kind,
+        }
+    }
+
+    pub fn chat(&self) -> Option<PackedChat> {
+        let bytes = STANDARD.decode(&self.chat).ok()?;
+        PackedChat::from_bytes(&bytes).ok()
+    }
+}
+
+/// Save `job` to `PENDING_FILE`
+pub fn add(config: &AppConfig, job: PendingJob) -> ResultGram<()> {
+    let _lock = PENDING_LOCK.lock().unwrap();
+    let mut jobs: Vec<PendingJob> = json_store::load(&config.pending_file);
+    jobs.push(job);
+    json_store::save(&config.pending_file, &jobs)
+}
+
+/// Jobs saved by the last shutdown, the file is removed so they run once
+pub fn take_all(config: &AppConfig) -> Vec<PendingJob> {
+    let _lock = PENDING_LOCK.lock().unwrap();
+    let jobs: Vec<PendingJob> = json_store::load(&config.pending_file);
+    if !jobs.is_empty() {
+        if let Err(e) = std::fs::remove_file(&config.pending_file) {
+            log::error!("Failed to remove {}: {e}", config.pending_file);
+        }
+    }
+    jobs
+}
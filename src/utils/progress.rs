@@ -0,0 +1,398 @@
+use super::custom_result::ResultGram;
+use super::download_registry::Download;
+use super::download_utils::SharedProgress;
+use super::metrics;
+use crate::app_config::AppConfig;
+use async_trait::async_trait;
+use grammers_client::types::Message;
+use grammers_client::{button, reply_markup, InputMessage};
+use std::fmt::Display;
+use std::future::Future;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often observers get `Bytes` or `Paused`
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What happened to a download since the last report
+#[derive(Clone, Debug)]
+pub enum ProgressEvent {
+    Started,
+    Bytes,
+    /// No bytes arrived since the last report
+    Paused,
+    Finished,
+    Failed(String),
+    /// Stopped by shutdown, continued after restart
+    Interrupted,
+}
+
+impl ProgressEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProgressEvent::Started => "started",
+            ProgressEvent::Bytes => "bytes",
+            ProgressEvent::Paused => "paused",
+            ProgressEvent::Finished => "finished",
+            ProgressEvent::Failed(_) => "failed",
+            ProgressEvent::Interrupted => "interrupted",
+        }
+    }
+}
+
+/// State of the download when an event is sent
+#[derive(Clone, Debug)]
+pub struct ProgressUpdate {
+    pub id: u64,
+    pub name: String,
+    pub downloaded: u64,
+    pub total: u64,
+    /// Bytes per second since the last report
+    pub speed: f64,
+    pub elapsed: Duration,
+}
+
+/// Receives the events of one download, Err is only logged
+#[async_trait]
+pub trait ProgressObserver: Send {
+    async fn on_event(
+        &mut self,
+        event: &ProgressEvent,
+        update: &ProgressUpdate,
+    ) -> Result<(), String>;
+}
+
+/// Sends the progress of one download to every attached observer
+pub struct ProgressReporter {
+    id: u64,
+    progress: Arc<SharedProgress>,
+    observers: Vec<Box<dyn ProgressObserver>>,
+    started: bool,
+    paused: bool,
+    start_time: Instant,
+    last_time: Instant,
+    last_downloaded: u64,
+}
+
+impl ProgressReporter {
+    pub fn new(download: &Download) -> Self {
+        ProgressReporter {
+            id: download.id(),
+            progress: download.progress().clone(),
+            observers: Vec::new(),
+            started: false,
+            paused: false,
+            start_time: Instant::now(),
+            last_time: Instant::now(),
+            last_downloaded: 0,
+        }
+    }
+
+    pub fn observe(mut self, observer: impl ProgressObserver + 'static) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    /// Log line, JSON-lines file and metrics, if enabled in config
+    pub fn with_config_sinks(mut self, config: &AppConfig) -> Self {
+        if config.progress_log {
+            self = self.observe(LogLine);
+        }
+        if let Some(path) = &config.progress_file {
+            self = self.observe(JsonLinesFile::new(path));
+        }
+        if config.metrics_address.is_some() {
+            self = self.observe(MetricsExporter);
+        }
+        self
+    }
+
+    /// Progress reply in the chat of `message`, plus the sinks from config
+    pub async fn for_chat(
+        message: &Message,
+        download: &Download,
+        config: &AppConfig,
+    ) -> ResultGram<Self> {
+        let progress_message = ProgressMessage::send(message, &download.button_data()).await?;
+        Ok(ProgressReporter::new(download)
+            .observe(progress_message)
+            .with_config_sinks(config))
+    }
+
+    /// Drive `future` to completion, while reporting every 5 sec
+    /// Can be called again for a retry, `Started` is only sent once
+    pub async fn run<F: Future>(&mut self, future: F) -> F::Output {
+        if !self.started {
+            self.started = true;
+            self.emit(ProgressEvent::Started).await;
+        }
+        tokio::pin!(future);
+        loop {
+            if let Ok(output) = tokio::time::timeout(REPORT_INTERVAL, &mut future).await {
+                return output;
+            }
+            let (_, downloaded, _) = self.progress.get();
+            let stalled = downloaded == self.last_downloaded;
+            let event = match stalled {
+                true if self.paused => continue,
+                true => ProgressEvent::Paused,
+                false => ProgressEvent::Bytes,
+            };
+            self.paused = stalled;
+            self.emit(event).await;
+        }
+    }
+
+    /// Send `Finished` or `Failed`, the chat reply is removed
+    pub async fn finish<T, E: Display>(mut self, result: &Result<T, E>) {
+        let event = match result {
+            Ok(_) => ProgressEvent::Finished,
+            Err(error) => ProgressEvent::Failed(error.to_string()),
+        };
+        self.emit(event).await;
+    }
+
+    /// Send `Interrupted`, the chat reply stays and says the download resumes
+    pub async fn interrupt(mut self) {
+        self.emit(ProgressEvent::Interrupted).await;
+    }
+
+    async fn emit(&mut self, event: ProgressEvent) {
+        let update = self.update();
+        for observer in self.observers.iter_mut() {
+            if let Err(error) = observer.on_event(&event, &update).await {
+                log::warn!("Failed to report progress: {error}");
+            }
+        }
+    }
+
+    fn update(&mut self) -> ProgressUpdate {
+        let (name, downloaded, total) = self.progress.get();
+        let delta = downloaded.saturating_sub(self.last_downloaded);
+        let speed = delta as f64 / self.last_time.elapsed().as_secs_f64().max(1.0);
+        self.last_downloaded = downloaded;
+        self.last_time = Instant::now();
+        ProgressUpdate {
+            id: self.id,
+            name,
+            downloaded,
+            total,
+            speed,
+            elapsed: self.start_time.elapsed(),
+        }
+    }
+}
+
+/// Progress reply with a Cancel button, deleted when the download ends
+pub struct ProgressMessage {
+    message: Message,
+    button_id: Vec<u8>,
+    last_progress_text: String,
+}
+
+impl ProgressMessage {
+    pub async fn send(reply_to: &Message, button_id: &[u8]) -> ResultGram<Self> {
+        let message = reply_to
+            .reply(
+                InputMessage::text("Downloading..").reply_markup(&reply_markup::inline(vec![
+                    vec![button::inline("Cancel", button_id)],
+                ])),
+            )
+            .await?;
+        Ok(ProgressMessage {
+            message,
+            button_id: button_id.to_vec(),
+            last_progress_text: "".to_string(),
+        })
+    }
+
+    async fn edit(&mut self, progress_text: String) -> Result<(), String> {
+        if self.last_progress_text == progress_text {
+            return Ok(());
+        }
+        self.message
+            .edit(
+                InputMessage::text(progress_text.clone()).reply_markup(&reply_markup::inline(
+                    vec![vec![button::inline("Cancel", self.button_id.clone())]],
+                )),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        self.last_progress_text = progress_text;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProgressObserver for ProgressMessage {
+    async fn on_event(
+        &mut self,
+        event: &ProgressEvent,
+        update: &ProgressUpdate,
+    ) -> Result<(), String> {
+        let speed_mbps = update.speed / (1024.0 * 1024.0);
+        let text = format_message(
+            &update.name,
+            update.downloaded as f64,
+            update.total as f64,
+            speed_mbps,
+        );
+        match event {
+            ProgressEvent::Started => Ok(()),
+            ProgressEvent::Bytes => self.edit(text).await,
+            ProgressEvent::Paused => {
+                self.edit(format!("{text}\n\nPaused, waiting for data"))
+                    .await
+            }
+            ProgressEvent::Finished | ProgressEvent::Failed(_) => {
+                self.message.delete().await.map_err(|e| e.to_string())
+            }
+            // Without the Cancel button, nothing is left to cancel
+            ProgressEvent::Interrupted => self
+                .message
+                .edit(format!("{text}\n\nInterrupted, will resume after restart"))
+                .await
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Format the message sent to Bot
+pub fn format_message(name: &str, downloaded_size: f64, total_size: f64, speed: f64) -> String {
+    let bar_width = 10;
+
+    let progress = if total_size > 0.0 {
+        (downloaded_size / total_size) * 100.0
+    } else {
+        0.0
+    };
+
+    let filled_blocks: usize = (progress / 100.0 * bar_width as f64).round() as usize;
+    let empty_blocks = bar_width - filled_blocks;
+
+    let progress_bar = format!(
+        "[{}] {:.2}%",
+        "🟩".repeat(filled_blocks).to_string() + &"⬜".repeat(empty_blocks),
+        progress
+    );
+
+    return format!(
+        "Downloading {name}
+        \n{:.1} MB of {:.2} MB done.\n\n{}
+        \nSpeed {:.1} MB/s",
+        downloaded_size / (1024.0 * 1024.0),
+        total_size / (1024.0 * 1024.0),
+        progress_bar,
+        speed,
+    );
+}
+
+/// One log line per event, enabled by `LOG_PROGRESS`
+pub struct LogLine;
+
+#[async_trait]
+impl ProgressObserver for LogLine {
+    async fn on_event(
+        &mut self,
+        event: &ProgressEvent,
+        update: &ProgressUpdate,
+    ) -> Result<(), String> {
+        let megabytes = |bytes: f64| bytes / (1024.0 * 1024.0);
+        let done = format!(
+            "{:.1} of {:.1} MB",
+            megabytes(update.downloaded as f64),
+            megabytes(update.total as f64)
+        );
+        match event {
+            ProgressEvent::Started => log::info!("Download {} started: {}", update.id, update.name),
+            ProgressEvent::Bytes => log::info!(
+                "Download {} {}: {done} at {:.1} MB/s",
+                update.id,
+                update.name,
+                megabytes(update.speed)
+            ),
+            ProgressEvent::Paused => {
+                log::info!("Download {} {}: paused at {done}", update.id, update.name)
+            }
+            ProgressEvent::Finished => log::info!(
+                "Download {} finished: {}, {done} in {} sec",
+                update.id,
+                update.name,
+                update.elapsed.as_secs()
+            ),
+            ProgressEvent::Failed(error) => {
+                log::info!("Download {} failed: {}, {error}", update.id, update.name)
+            }
+            ProgressEvent::Interrupted => log::info!(
+                "Download {} interrupted: {}, {done}",
+                update.id,
+                update.name
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Appends one JSON object per event to `PROGRESS_FILE`
+pub struct JsonLinesFile {
+    path: String,
+}
+
+impl JsonLinesFile {
+    pub fn new(path: &str) -> Self {
+        JsonLinesFile {
+            path: path.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl ProgressObserver for JsonLinesFile {
+    async fn on_event(
+        &mut self,
+        event: &ProgressEvent,
+        update: &ProgressUpdate,
+    ) -> Result<(), String> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let error = match event {
+            ProgressEvent::Failed(error) => Some(error.as_str()),
+            _ => None,
+        };
+        let line = serde_json::json!({
+            "time": time,
+            "event": event.name(),
+            "id": update.id,
+            "name": update.name,
+            "downloaded": update.downloaded,
+            "total": update.total,
+            "speed": update.speed.round() as u64,
+            "elapsed": update.elapsed.as_secs(),
+            "error": error,
+        });
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("{}: {e}", self.path))?;
+        writeln!(file, "{line}").map_err(|e| format!("{}: {e}", self.path))
+    }
+}
+
+/// Feeds the metrics served on `METRICS_ADDRESS`
+pub struct MetricsExporter;
+
+#[async_trait]
+impl ProgressObserver for MetricsExporter {
+    async fn on_event(
+        &mut self,
+        event: &ProgressEvent,
+        update: &ProgressUpdate,
+    ) -> Result<(), String> {
+        metrics::record(event, update);
+        Ok(())
+    }
+}
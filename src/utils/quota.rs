@@ -0,0 +1,305 @@
+use super::audit::{audit, AuditEntry};
+use super::custom_result::ResultGram;
+use super::external_tool::parse_size;
+use super::json_store::{self, JsonStore};
+use crate::app_config::AppConfig;
+use grammers_client::types::Message;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    /// Usage of `USAGE_FILE` by user id
+    static ref USAGE: JsonStore<HashMap<i64, Usage>> = JsonStore::default();
+    /// Running downloads per user
+    static ref ACTIVE_DOWNLOADS: Mutex<HashMap<i64, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Limits of a user, everything is unlimited when not set
+/// Sizes can be bytes or strings like `500M` or `2G`, `2GiB` for 1024-based units
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserLimits {
+    #[serde(deserialize_with = "deserialize_size")]
+    pub daily_bytes: Option<u64>,
+    #[serde(deserialize_with = "deserialize_size")]
+    pub monthly_bytes: Option<u64>,
+    #[serde(deserialize_with = "deserialize_size")]
+    pub max_file_size: Option<u64>,
+    pub max_concurrent: Option<usize>,
+    /// Allowed entries of `DOWNLOAD_DIRECTORY`, all of them if empty
+    pub directories: Vec<String>,
+    /// Download into `<directory>/<user id>`
+    pub home_subfolder: bool,
+}
+
+/// `[limits]` of the config, `default` applies to users without their own entry
+#[derive(Clone, Default)]
+pub struct Limits {
+    pub default: UserLimits,
+    pub users: HashMap<i64, UserLimits>,
+}
+
+/// Bytes downloaded by a user in the current day and month
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Usage {
+    day: u64,
+    day_bytes: u64,
+    month: u64,
+    month_bytes: u64,
+}
+
+/// Held while a download runs, counts towards `max_concurrent`
+pub struct ActiveDownload {
+    user_id: i64,
+    /// Bytes the download may transfer, smallest of file size limit and quota left
+    limit: Option<u64>,
+}
+
+impl ActiveDownload {
+    pub fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    /// Add the bytes the download actually transferred to the user's usage
+    pub fn record(&self, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        if let Err(e) = record_usage(&AppConfig::current(), self.user_id, bytes) {
+            log::error!("Failed to record usage of {}: {e}", self.user_id);
+        }
+    }
+}
+
+impl Drop for ActiveDownload {
+    fn drop(&mut self) {
+        let mut active = ACTIVE_DOWNLOADS.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.user_id) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                active.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Bytes(bytes)) => Ok(Some(bytes)),
+        Some(Size::Text(text)) => parse_size(&text)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid size {text}"))),
+    }
+}
+
+/// Limits of `user_id`, admin is only limited by an own entry
+pub fn limits_of(config: &AppConfig, user_id: i64) -> UserLimits {
+    match config.limits.users.get(&user_id) {
+        Some(limits) => limits.clone(),
+        None if user_id == config.user_id => UserLimits::default(),
+        None => config.limits.default.clone(),
+    }
+}
+
+/// Directories the user can download to, with the home subfolder applied
+pub fn allowed_directories(config: &AppConfig, user_id: i64) -> Vec<String> {
+    let limits = limits_of(config, user_id);
+    config
+        .download_directory
+        .iter()
+        .filter(|directory| limits.directories.is_empty() || limits.directories.contains(directory))
+        .map(|directory| match limits.home_subfolder {
+            true => format!("{}/{user_id}", directory.trim_end_matches('/')),
+            false => directory.clone(),
+        })
+        .collect()
+}
+
+/// Check size, quota and running downloads before a download starts
+/// Err has the message for the user, with what is left of the allowance
+pub fn start_download(
+    config: &AppConfig,
+    user_id: i64,
+    size: Option<u64>,
+) -> Result<ActiveDownload, String> {
+    let limits = limits_of(config, user_id);
+    let size = size.unwrap_or(0);
+
+    if let Some(max_file_size) = limits.max_file_size {
+        if size > max_file_size {
+            return Err(format!(
+                "File is too big, your limit is {} per file",
+                format_size(max_file_size)
+            ));
+        }
+    }
+
+    let (day_left, month_left) = remaining(config, user_id, &limits);
+    let left = match (day_left, month_left) {
+        (Some(day), Some(month)) => Some(day.min(month)),
+        (day, month) => day.or(month),
+    };
+    if let Some(left) = left {
+        if left == 0 || size > left {
+            return Err(format!(
+                "Download quota exceeded, {} needed\n{}",
+                format_size(size),
+                allowance_text(day_left, month_left)
+            ));
+        }
+    }
+
+    let mut active = ACTIVE_DOWNLOADS.lock().unwrap();
+    let count = active.entry(user_id).or_insert(0);
+    if let Some(max_concurrent) = limits.max_concurrent {
+        if *count >= max_concurrent {
+            return Err(format!(
+                "You already have {count} downloads running, wait for one to finish"
+            ));
+        }
+    }
+    *count += 1;
+    let limit = match (limits.max_file_size, left) {
+        (Some(max_file_size), Some(left)) => Some(max_file_size.min(left)),
+        (max_file_size, left) => max_file_size.or(left),
+    };
+    Ok(ActiveDownload { user_id, limit })
+}
+
+/// Entry point of every download started from a chat, checks the limits of the sender
+/// Refusals are audited and replied, None if the download must not start
+pub async fn admit(
+    message: &Message,
+    name: &str,
+    size: Option<u64>,
+) -> ResultGram<Option<ActiveDownload>> {
+    let config = AppConfig::current();
+    let user_id = message.sender().map(|sender| sender.id()).unwrap_or(0);
+    let refusal = match start_download(&config, user_id, size) {
+        Ok(active_download) => return Ok(Some(active_download)),
+        Err(refusal) => refusal,
+    };
+
+    let mut entry = AuditEntry::from_message(message, "download")
+        .file_name(name)
+        .result(&format!("refused: {refusal}"));
+    if let Some(size) = size {
+        entry = entry.size(size);
+    }
+    audit(&config, entry);
+    log::info!("Refused download of {name} for {user_id}: {refusal}");
+    message.reply(refusal).await?;
+    Ok(None)
+}
+
+/// Add downloaded bytes to the user's daily and monthly usage
+pub fn record_usage(config: &AppConfig, user_id: i64, bytes: u64) -> ResultGram<()> {
+    let (day, month) = today();
+    USAGE.with(&config.usage_file, |all_usage| {
+        let usage = all_usage.entry(user_id).or_default();
+        if usage.day != day {
+            usage.day = day;
+            usage.day_bytes = 0;
+        }
+        if usage.month != month {
+            usage.month = month;
+            usage.month_bytes = 0;
+        }
+        usage.day_bytes += bytes;
+        usage.month_bytes += bytes;
+        json_store::save(&config.usage_file, all_usage)
+    })
+}
+
+/// What is left of the allowance, e.g. `Left today: 1.5 GB, this month: 20.0 GB`
+pub fn remaining_allowance(config: &AppConfig, user_id: i64) -> Option<String> {
+    let limits = limits_of(config, user_id);
+    match remaining(config, user_id, &limits) {
+        (None, None) => None,
+        (day_left, month_left) => Some(allowance_text(day_left, month_left)),
+    }
+}
+
+fn remaining(config: &AppConfig, user_id: i64, limits: &UserLimits) -> (Option<u64>, Option<u64>) {
+    let (day, month) = today();
+    let usage = USAGE.with(&config.usage_file, |all_usage| {
+        all_usage.get(&user_id).cloned().unwrap_or_default()
+    });
+    let day_used = if usage.day == day { usage.day_bytes } else { 0 };
+    let month_used = if usage.month == month {
+        usage.month_bytes
+    } else {
+        0
+    };
+    (
+        limits
+            .daily_bytes
+            .map(|limit| limit.saturating_sub(day_used)),
+        limits
+            .monthly_bytes
+            .map(|limit| limit.saturating_sub(month_used)),
+    )
+}
+
+fn allowance_text(day_left: Option<u64>, month_left: Option<u64>) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    if let Some(day_left) = day_left {
+        parts.push(format!("today: {}", format_size(day_left)));
+    }
+    if let Some(month_left) = month_left {
+        parts.push(format!("this month: {}", format_size(month_left)));
+    }
+    format!("Left {}", parts.join(", "))
+}
+
+/// 1000-based like the sizes in `[limits]`
+fn format_size(bytes: u64) -> String {
+    let bytes = bytes as f64;
+    if bytes >= 1e9 {
+        format!("{:.1} GB", bytes / 1e9)
+    } else {
+        format!("{:.1} MB", bytes / 1e6)
+    }
+}
+
+/// Current day since epoch and month as `year * 12 + month`, in UTC
+fn today() -> (u64, u64) {
+    let seconds = json_store::now();
+    let day = seconds / 86400;
+
+    // Civil date from days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = day as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (day, (year * 12 + month - 1) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_use_limit_units() {
+        assert_eq!(format_size(parse_size("2G").unwrap()), "2.0 GB");
+        assert_eq!(format_size(parse_size("500M").unwrap()), "500.0 MB");
+        assert_eq!(format_size(1_500_000_000), "1.5 GB");
+    }
+}
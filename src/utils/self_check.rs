@@ -0,0 +1,159 @@
+use super::aria2::Aria2Client;
+use super::external_tool::is_installed;
+use super::gdrive::GDOWN_PROGRAM;
+use super::helper::resolve_user;
+use super::ytdlp::YTDLP_PROGRAM;
+use crate::app_config::AppConfig;
+use grammers_client::session::Session;
+use grammers_client::Client;
+use std::path::Path;
+
+/// Less free space than this in a download directory is reported
+const MIN_FREE_SPACE: u64 = 1024 * 1024 * 1024;
+/// Missing tools only disable a feature, Drive fallback and video sites
+const OPTIONAL_TOOLS: [&str; 2] = [GDOWN_PROGRAM, YTDLP_PROGRAM];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Failed,
+}
+
+pub struct Check {
+    pub status: CheckStatus,
+    pub text: String,
+}
+
+impl Check {
+    pub fn ok(text: String) -> Self {
+        Check {
+            status: CheckStatus::Ok,
+            text,
+        }
+    }
+
+    pub fn warning(text: String) -> Self {
+        Check {
+            status: CheckStatus::Warning,
+            text,
+        }
+    }
+
+    pub fn failed(text: String) -> Self {
+        Check {
+            status: CheckStatus::Failed,
+            text,
+        }
+    }
+
+    pub fn line(&self) -> String {
+        let status = match self.status {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warning => "warning",
+            CheckStatus::Failed => "failed",
+        };
+        format!("[{status}] {}", self.text)
+    }
+}
+
+/// Everything that can be checked without Telegram
+pub async fn check_environment(config: &AppConfig, session_file: &str) -> Vec<Check> {
+    let mut checks = Vec::new();
+    for directory in &config.download_directory {
+        checks.extend(check_directory(directory));
+    }
+    for tool in OPTIONAL_TOOLS {
+        checks.push(match is_installed(tool) {
+            true => Check::ok(format!("{tool} is installed")),
+            false => Check::warning(format!("{tool} is not installed")),
+        });
+    }
+    if let Some(aria2) = Aria2Client::from_config(config) {
+        let version = aria2.version().await.map_err(|e| e.to_string());
+        checks.push(match version {
+            Ok(version) => Check::ok(format!("aria2 {version} is reachable")),
+            Err(e) => Check::failed(format!("aria2 is not reachable: {e}")),
+        });
+    }
+    checks.push(check_session(session_file));
+    checks
+}
+
+/// Startup message and access requests go to admin, so admin must be reachable
+pub async fn check_admin(bot: &Client, config: &AppConfig) -> Check {
+    let admin = resolve_user(bot, config.user_id)
+        .await
+        .map_err(|e| e.to_string());
+    match admin {
+        Ok(admin) => Check::ok(format!("Admin {} ({}) found", admin.name(), admin.id())),
+        Err(e) => Check::failed(format!(
+            "Admin {} can't be found: {e}, check USER_ID and send /start to the bot",
+            config.user_id
+        )),
+    }
+}
+
+/// One line per check, problems first
+pub fn report(checks: &[Check]) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for status in [CheckStatus::Failed, CheckStatus::Warning, CheckStatus::Ok] {
+        let matching = checks.iter().filter(|check| check.status == status);
+        lines.extend(matching.map(|check| check.line()));
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Failed)
+        .count();
+    match failed {
+        0 => lines.push("All checks passed".to_string()),
+        _ => lines.push(format!("{failed} checks failed")),
+    }
+    lines.join("\n")
+}
+
+pub fn has_failures(checks: &[Check]) -> bool {
+    checks
+        .iter()
+        .any(|check| check.status == CheckStatus::Failed)
+}
+
+/// Exists, is writable and has some free space
+fn check_directory(directory: &str) -> Vec<Check> {
+    let path = Path::new(directory);
+    if !path.is_dir() {
+        return vec![Check::failed(format!("{directory} does not exist"))];
+    }
+
+    let test_file = path.join(".telegram_bot_check");
+    let writable = std::fs::write(&test_file, b"").and_then(|_| std::fs::remove_file(&test_file));
+    let mut checks = vec![match writable {
+        Ok(()) => Check::ok(format!("{directory} is writable")),
+        Err(e) => Check::failed(format!("{directory} is not writable: {e}")),
+    }];
+
+    checks.push(match fs2::available_space(path) {
+        Ok(free) if free < MIN_FREE_SPACE => Check::warning(format!(
+            "{directory} has only {:.1} GB free",
+            free as f64 / 1073741824.0
+        )),
+        Ok(free) => Check::ok(format!(
+            "{directory} has {:.1} GB free",
+            free as f64 / 1073741824.0
+        )),
+        Err(e) => Check::warning(format!("Free space of {directory} unknown: {e}")),
+    });
+    checks
+}
+
+fn check_session(session_file: &str) -> Check {
+    if !Path::new(session_file).exists() {
+        return Check::warning(format!(
+            "{session_file} not found, the bot will sign in with BOT_TOKEN"
+        ));
+    }
+    match Session::load_file(session_file) {
+        Ok(_) => Check::ok(format!("{session_file} is valid")),
+        Err(e) => Check::failed(format!("{session_file} is invalid: {e}")),
+    }
+}
@@ -0,0 +1,72 @@
+use super::download_registry;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often running downloads are checked while shutting down
+const WAIT_INTERVAL: Duration = Duration::from_millis(500);
+/// Time interrupted downloads get to save their state and update their message
+const INTERRUPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// True once SIGINT or SIGTERM was received, no new jobs are started after that
+pub fn is_shutting_down() -> bool {
+    SHUTTING_DOWN.load(Ordering::SeqCst)
+}
+
+/// Wait for SIGINT (Ctrl+C) or SIGTERM
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => log::warn!("Can't listen for SIGTERM: {e}"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Let running downloads finish within `grace_period`, then interrupt the rest
+/// Interrupted downloads save their state to resume after restart
+pub async fn shutdown(grace_period: Duration) {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+    let running = download_registry::list().len();
+    if running > 0 {
+        log::info!(
+            "Shutting down, waiting up to {} sec for {running} downloads",
+            grace_period.as_secs()
+        );
+    }
+    if wait_for_downloads(grace_period).await {
+        log::info!("Shutting down...");
+        return;
+    }
+
+    let interrupted = download_registry::interrupt_all();
+    log::info!("Interrupting {interrupted} downloads, they resume after restart");
+    if !wait_for_downloads(INTERRUPT_TIMEOUT).await {
+        log::warn!("Some downloads didn't stop in time, they can't be resumed");
+    }
+    log::info!("Shutting down...");
+}
+
+/// True if no download is left running within `timeout`
+async fn wait_for_downloads(timeout: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        if download_registry::list().is_empty() {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        tokio::time::sleep(WAIT_INTERVAL).await;
+    }
+}
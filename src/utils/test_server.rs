@@ -0,0 +1,116 @@
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+/// Response of the stub server
+pub struct Reply {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn html(body: impl Into<String>) -> Self {
+        Reply {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    pub fn file(body: Vec<u8>) -> Self {
+        Reply {
+            status: 200,
+            content_type: "application/octet-stream",
+            body,
+        }
+    }
+
+    pub fn json(body: &serde_json::Value) -> Self {
+        Reply {
+            status: 200,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    /// Error page with `status`
+    pub fn error(status: u16) -> Self {
+        Reply {
+            status,
+            content_type: "text/plain",
+            body: format!("error {status}").into_bytes(),
+        }
+    }
+}
+
+/// Local HTTP server standing in for Drive or aria2
+/// `handler` gets the requested url and body, returns the base url to send requests to
+pub async fn serve<H>(handler: H) -> Url
+where
+    H: Fn(&Url, &[u8]) -> Reply + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    let handler = Arc::new(handler);
+    let base = base_url.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let handler = handler.clone();
+            let base = base.clone();
+            tokio::spawn(async move {
+                let _ = answer(stream, &base, handler.as_ref()).await;
+            });
+        }
+    });
+    base_url
+}
+
+async fn answer<H>(mut stream: TcpStream, base: &Url, handler: &H) -> std::io::Result<()>
+where
+    H: Fn(&Url, &[u8]) -> Reply,
+{
+    let mut request: Vec<u8> = Vec::new();
+    let mut buffer = [0u8; 4096];
+    let head_end = loop {
+        if let Some(index) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break index + 4;
+        }
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    };
+
+    let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+    let content_length: usize = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0);
+    while request.len() < head_end + content_length {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let target = head.split_whitespace().nth(1).unwrap_or("/");
+    let url = base.join(target).unwrap();
+    let reply = handler(&url, &request[head_end..]);
+    let head = format!(
+        "HTTP/1.1 {} Status\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        reply.status,
+        reply.content_type,
+        reply.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    for chunk in reply.body.chunks(16 * 1024) {
+        stream.write_all(chunk).await?;
+    }
+    Ok(())
+}
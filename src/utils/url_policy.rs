@@ -0,0 +1,319 @@
+use super::custom_result::ResultGram;
+use crate::app_config::AppConfig;
+use reqwest::{header, redirect, Response};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::lookup_host;
+use url::{Host, Url};
+
+const MAX_REDIRECTS: usize = 10;
+
+/// Decides which links the bot is allowed to fetch
+/// Private, loopback and link-local addresses are blocked, unless added to `URL_ALLOWLIST`
+#[derive(Clone, Default)]
+pub struct UrlPolicy {
+    allowed_hosts: Vec<String>,
+    allowed_networks: Vec<(IpAddr, u8)>,
+}
+
+impl UrlPolicy {
+    /// Exceptions can be host names (`nas.local`), addresses (`192.168.1.10`) or networks (`10.0.0.0/8`)
+    pub fn new(exceptions: &[String]) -> Self {
+        let mut policy = UrlPolicy::default();
+        for exception in exceptions {
+            match parse_network(exception) {
+                Some(network) => policy.allowed_networks.push(network),
+                None => policy.allowed_hosts.push(exception.to_lowercase()),
+            }
+        }
+        policy
+    }
+
+    pub fn from_config(config: &AppConfig) -> Self {
+        UrlPolicy::new(&config.url_allowlist)
+    }
+
+    fn is_allowed_ip(&self, ip: IpAddr) -> bool {
+        if !is_restricted_ip(ip) {
+            return true;
+        }
+        self.allowed_networks
+            .iter()
+            .any(|(network, prefix)| network_contains(*network, *prefix, ip))
+    }
+
+    /// Resolve the url and make sure every address it points to is allowed
+    pub async fn resolve(&self, url: &Url) -> ResultGram<Vec<SocketAddr>> {
+        match url.scheme() {
+            "http" | "https" => {}
+            scheme => return Err(format!("Unsupported url scheme: {scheme}").into()),
+        }
+        let port = url.port_or_known_default().ok_or("Url has no port")?;
+        let (host_name, addresses): (String, Vec<SocketAddr>) =
+            match url.host().ok_or("Url has no host")? {
+                Host::Ipv4(ip) => (ip.to_string(), vec![SocketAddr::new(ip.into(), port)]),
+                Host::Ipv6(ip) => (ip.to_string(), vec![SocketAddr::new(ip.into(), port)]),
+                Host::Domain(domain) => (
+                    domain.to_lowercase(),
+                    lookup_host((domain, port)).await?.collect(),
+                ),
+            };
+
+        if addresses.is_empty() {
+            return Err(format!("Could not resolve {host_name}").into());
+        }
+        if self.allowed_hosts.contains(&host_name) {
+            return Ok(addresses);
+        }
+        if let Some(blocked) = addresses.iter().find(|a| !self.is_allowed_ip(a.ip())) {
+            return Err(format!("Access to {host_name} ({}) is not allowed", blocked.ip()).into());
+        }
+        Ok(addresses)
+    }
+
+    /// Send a GET request, redirects are followed manually so every hop is checked again
+    /// Connections are pinned to the checked addresses, so DNS can't change in between
+    pub async fn get(&self, url: Url) -> ResultGram<Response> {
+        let mut url = url;
+        for _ in 0..=MAX_REDIRECTS {
+            let addresses = self.resolve(&url).await?;
+            let mut builder = reqwest::Client::builder().redirect(redirect::Policy::none());
+            if let Some(Host::Domain(domain)) = url.host() {
+                builder = builder.resolve_to_addrs(domain, &addresses);
+            }
+            let response = builder.build()?.get(url.clone()).send().await?;
+
+            if !response.status().is_redirection() {
+                return Ok(response);
+            }
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .ok_or("Redirect without location")?
+                .to_str()?;
+            url = url.join(location)?;
+            log::debug!("Redirected to {url}");
+        }
+        Err(format!("Too many redirects, stopped at {url}").into())
+    }
+}
+
+/// Addresses that should never be reached from a user supplied link
+pub fn is_restricted_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_restricted_ipv4(ip),
+        IpAddr::V6(ip) => {
+            if ip.is_loopback() || ip.is_unspecified() {
+                return true;
+            }
+            if let Some(embedded) = embedded_ipv4(ip) {
+                return is_restricted_ipv4(embedded);
+            }
+            let segments = ip.segments();
+            ip.is_multicast()
+                // Unique local fc00::/7
+                || (segments[0] & 0xfe00) == 0xfc00
+                // Link local fe80::/10
+                || (segments[0] & 0xffc0) == 0xfe80
+                // Local use NAT64 64:ff9b:1::/48, the IPv4 part depends on the local setup
+                || segments[..3] == [0x64, 0xff9b, 1]
+        }
+    }
+}
+
+/// IPv4 address wrapped in an IPv6 one, which reaches the IPv4 host
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let octets = ip.octets();
+    let last_32 = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+    match segments {
+        // Mapped ::ffff:a.b.c.d
+        [0, 0, 0, 0, 0, 0xffff, _, _] => Some(last_32),
+        // Compatible ::a.b.c.d, deprecated but still routed by some stacks
+        [0, 0, 0, 0, 0, 0, _, _] => Some(last_32),
+        // Well-known NAT64 64:ff9b::/96
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(last_32),
+        // 6to4 2002:a.b.c.d::/48
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+fn is_restricted_ipv4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        // Carrier grade NAT 100.64.0.0/10
+        || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        // "This network" 0.0.0.0/8
+        || octets[0] == 0
+        // Reserved 240.0.0.0/4
+        || octets[0] >= 240
+}
+
+/// Parse `ip` or `ip/prefix`
+fn parse_network(value: &str) -> Option<(IpAddr, u8)> {
+    let (ip, prefix) = match value.split_once('/') {
+        Some((ip, prefix)) => (ip.parse::<IpAddr>().ok()?, prefix.parse::<u8>().ok()?),
+        None => {
+            let ip = value.parse::<IpAddr>().ok()?;
+            (ip, if ip.is_ipv4() { 32 } else { 128 })
+        }
+    };
+    let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+    if prefix > max_prefix {
+        return None;
+    }
+    Some((ip, prefix))
+}
+
+fn network_contains(network: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            (u32::from(network) & mask) == (u32::from(ip) & mask)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            (u128::from(network) & mask) == (u128::from(ip) & mask)
+        }
+        (IpAddr::V6(network), IpAddr::V4(ip)) => {
+            network_contains(IpAddr::V6(network), prefix, IpAddr::V6(ip.to_ipv6_mapped()))
+        }
+        (IpAddr::V4(_), IpAddr::V6(ip)) => match embedded_ipv4(ip) {
+            Some(embedded) => network_contains(network, prefix, IpAddr::V4(embedded)),
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restricted_addresses() {
+        let blocked = [
+            "10.1.2.3",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "127.0.0.1",
+            "127.255.255.254",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "100.127.255.255",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "::ffff:169.254.169.254",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b:1::a00:1",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+        ];
+        for ip in blocked {
+            assert!(
+                is_restricted_ip(ip.parse().unwrap()),
+                "{ip} should be blocked"
+            );
+        }
+    }
+
+    #[test]
+    fn public_addresses() {
+        let allowed = [
+            "1.1.1.1",
+            "8.8.8.8",
+            "93.184.216.34",
+            "172.32.0.1",
+            "100.128.0.1",
+            "169.255.0.1",
+            "223.255.255.255",
+            "2606:4700:4700::1111",
+            "2001:4860:4860::8888",
+            "::ffff:1.1.1.1",
+            "::8.8.8.8",
+            "64:ff9b::808:808",
+            "2002:808:808::",
+        ];
+        for ip in allowed {
+            assert!(
+                !is_restricted_ip(ip.parse().unwrap()),
+                "{ip} should be allowed"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_networks() {
+        let cases = [
+            ("10.0.0.0/8", Some(("10.0.0.0", 8))),
+            ("192.168.1.10", Some(("192.168.1.10", 32))),
+            ("fd00::/8", Some(("fd00::", 8))),
+            ("::1", Some(("::1", 128))),
+            ("10.0.0.0/33", None),
+            ("fd00::/129", None),
+            ("10.0.0.0/x", None),
+            ("nas.local", None),
+            ("", None),
+        ];
+        for (value, expected) in cases {
+            let expected = expected.map(|(ip, prefix)| (ip.parse::<IpAddr>().unwrap(), prefix));
+            assert_eq!(parse_network(value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn networks_contain() {
+        let cases = [
+            ("10.0.0.0/8", "10.255.1.2", true),
+            ("10.0.0.0/8", "11.0.0.1", false),
+            ("192.168.1.10", "192.168.1.10", true),
+            ("192.168.1.10", "192.168.1.11", false),
+            ("0.0.0.0/0", "8.8.8.8", true),
+            ("fd00::/8", "fd12::1", true),
+            ("fd00::/8", "fe80::1", false),
+            ("10.0.0.0/8", "::ffff:10.0.0.1", true),
+            ("10.0.0.0/8", "64:ff9b::a00:1", true),
+            ("10.0.0.0/8", "::ffff:11.0.0.1", false),
+            ("::ffff:0:0/96", "10.0.0.1", true),
+            ("10.0.0.0/8", "fd00::1", false),
+        ];
+        for (network, ip, expected) in cases {
+            let (network, prefix) = parse_network(network).unwrap();
+            let ip: IpAddr = ip.parse().unwrap();
+            assert_eq!(
+                network_contains(network, prefix, ip),
+                expected,
+                "{network}/{prefix} {ip}"
+            );
+        }
+    }
+
+    #[test]
+    fn allowlist_exceptions() {
+        let policy = UrlPolicy::new(&["192.168.1.0/24".to_string(), "NAS.local".to_string()]);
+        assert!(policy.is_allowed_ip("192.168.1.20".parse().unwrap()));
+        assert!(policy.is_allowed_ip("::ffff:192.168.1.20".parse().unwrap()));
+        assert!(!policy.is_allowed_ip("192.168.2.20".parse().unwrap()));
+        assert!(!policy.is_allowed_ip("127.0.0.1".parse().unwrap()));
+        assert!(policy.is_allowed_ip("8.8.8.8".parse().unwrap()));
+        assert_eq!(policy.allowed_hosts, vec!["nas.local".to_string()]);
+    }
+}
@@ -0,0 +1,73 @@
+use super::access_control::Role;
+use super::custom_result::ResultGram;
+use super::json_store::{self, now, JsonStore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+lazy_static::lazy_static! {
+    /// Users of `USERS_FILE` by id
+    static ref USERS: JsonStore<HashMap<i64, StoredUser>> = JsonStore::with_loader(load);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UserStatus {
+    Pending,
+    Approved,
+    Denied,
+    Blocked,
+}
+
+/// User that asked for access, with the admin decision
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredUser {
+    pub id: i64,
+    pub name: String,
+    pub access_hash: Option<i64>,
+    pub status: UserStatus,
+    pub role: Role,
+    pub updated_at: u64,
+}
+
+impl StoredUser {
+    pub fn new(id: i64, name: &str, access_hash: Option<i64>) -> Self {
+        StoredUser {
+            id,
+            name: name.to_string(),
+            access_hash,
+            status: UserStatus::Pending,
+            role: Role::Downloader,
+            updated_at: now(),
+        }
+    }
+}
+
+/// The file is a list of users
+fn load(path: &str) -> HashMap<i64, StoredUser> {
+    json_store::load::<Vec<StoredUser>>(path)
+        .into_iter()
+        .map(|user| (user.id, user))
+        .collect()
+}
+
+pub fn get_user(path: &str, user_id: i64) -> Option<StoredUser> {
+    USERS.with(path, |users| users.get(&user_id).cloned())
+}
+
+/// All stored users, sorted by name
+pub fn list_users(path: &str) -> Vec<StoredUser> {
+    let mut users: Vec<StoredUser> = USERS.with(path, |users| users.values().cloned().collect());
+    users.sort_by_key(|user| user.name.to_lowercase());
+    users
+}
+
+/// Insert or replace the user and write the file
+pub fn save_user(path: &str, mut user: StoredUser) -> ResultGram<()> {
+    user.updated_at = now();
+    USERS.with(path, |users| {
+        users.insert(user.id, user);
+        let mut all: Vec<&StoredUser> = users.values().collect();
+        all.sort_by_key(|user| user.id);
+        json_store::save(path, &all)
+    })
+}
@@ -0,0 +1,242 @@
+use super::custom_result::ResultGram;
+use super::external_tool::run_tool;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+use url::{Host, Url};
+
+pub const YTDLP_PROGRAM: &str = "yt-dlp";
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+const PROGRESS_PREFIX: &str = "[progress]";
+const MAX_QUALITY_OPTIONS: usize = 6;
+/// Only site extractors, the generic one fetches whatever the page links to
+const EXTRACTORS: &str = "default,-generic";
+
+/// Sites sent to yt-dlp when `VIDEO_DOMAINS` is empty, sub domains included
+pub const DEFAULT_VIDEO_DOMAINS: &[&str] = &[
+    "youtube.com",
+    "youtu.be",
+    "vimeo.com",
+    "dailymotion.com",
+    "twitch.tv",
+    "twitter.com",
+    "x.com",
+    "instagram.com",
+    "facebook.com",
+    "tiktok.com",
+    "reddit.com",
+    "soundcloud.com",
+    "bilibili.com",
+];
+
+pub struct VideoInfo {
+    pub title: String,
+    pub extractor: String,
+    /// Available video heights, highest first
+    pub heights: Vec<u64>,
+}
+
+/// Quality choices offered to user
+#[derive(Clone, Serialize, Deserialize)]
+pub enum VideoQuality {
+    Best,
+    Height(u64),
+    AudioOnly,
+}
+
+impl VideoQuality {
+    pub fn label(&self) -> String {
+        match self {
+            VideoQuality::Best => "Best".to_string(),
+            VideoQuality::Height(height) => format!("{height}p"),
+            VideoQuality::AudioOnly => "Audio only".to_string(),
+        }
+    }
+
+    fn format_selector(&self) -> String {
+        match self {
+            VideoQuality::Best => "bv*+ba/b".to_string(),
+            VideoQuality::Height(height) => format!("bv*[height<={height}]+ba/b[height<={height}]"),
+            VideoQuality::AudioOnly => "ba/b".to_string(),
+        }
+    }
+}
+
+impl VideoInfo {
+    pub fn quality_options(&self) -> Vec<VideoQuality> {
+        let mut options = vec![VideoQuality::Best];
+        options.extend(
+            self.heights
+                .iter()
+                .take(MAX_QUALITY_OPTIONS)
+                .map(|height| VideoQuality::Height(*height)),
+        );
+        options.push(VideoQuality::AudioOnly);
+        options
+    }
+}
+
+/// yt-dlp follows redirects and resolves hosts itself, bypassing `UrlPolicy`
+/// so it only gets links of well known video sites
+pub fn is_video_site(url: &Url, domains: &[String]) -> bool {
+    let host = match url.host() {
+        Some(Host::Domain(host)) => host.trim_end_matches('.').to_lowercase(),
+        _ => return false,
+    };
+    let matches = |domain: &str| {
+        let domain = domain.trim_start_matches('.').to_lowercase();
+        host == domain || host.ends_with(&format!(".{domain}"))
+    };
+    if domains.is_empty() {
+        return DEFAULT_VIDEO_DOMAINS.iter().any(|domain| matches(domain));
+    }
+    domains.iter().any(|domain| matches(domain))
+}
+
+/// Ask yt-dlp about the link, returns None if it's not a video site it knows
+/// Links only matched by the generic extractor are left to the plain url downloader
+pub async fn probe(url: &str) -> Option<VideoInfo> {
+    let mut command = Command::new(YTDLP_PROGRAM);
+    command
+        .args(["--dump-single-json", "--no-playlist", "--no-warnings"])
+        .args(["--use-extractors", EXTRACTORS])
+        .arg("--")
+        .arg(url)
+        .kill_on_drop(true);
+
+    let output = match tokio::time::timeout(PROBE_TIMEOUT, command.output()).await {
+        Ok(Ok(output)) if output.status.success() => output,
+        Ok(Ok(output)) => {
+            log::debug!(
+                "yt-dlp can't handle {url}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            return None;
+        }
+        Ok(Err(e)) => {
+            log::error!("Failed to run yt-dlp: {e}");
+            return None;
+        }
+        Err(_) => {
+            log::warn!("yt-dlp probe timed out for {url}");
+            return None;
+        }
+    };
+
+    let json: Value = serde_json::from_slice(&output.stdout).ok()?;
+    let extractor = json["extractor"].as_str().unwrap_or("generic").to_string();
+    if extractor == "generic" {
+        return None;
+    }
+
+    let mut heights: Vec<u64> = json["formats"]
+        .as_array()
+        .map(|formats| {
+            formats
+                .iter()
+                .filter(|format| format["vcodec"].as_str() != Some("none"))
+                .filter_map(|format| format["height"].as_u64())
+                .collect()
+        })
+        .unwrap_or_default();
+    heights.sort_unstable_by(|a, b| b.cmp(a));
+    heights.dedup();
+
+    Some(VideoInfo {
+        title: json["title"].as_str().unwrap_or(url).to_string(),
+        extractor,
+        heights,
+    })
+}
+
+/// Download with yt-dlp into `directory`, returns the final file path
+/// `on_progress` gets called with title, downloaded and total bytes
+pub async fn download<F>(
+    url: &str,
+    quality: &VideoQuality,
+    directory: &Path,
+    timeout: Duration,
+    cancel_token: &CancellationToken,
+    on_progress: &F,
+) -> ResultGram<PathBuf>
+where
+    F: Fn(&str, u64, u64) + Sync,
+{
+    let mut command = Command::new(YTDLP_PROGRAM);
+    command
+        .args(["--no-playlist", "--newline", "--progress", "--no-warnings"])
+        .args(["--use-extractors", EXTRACTORS])
+        .arg("-f")
+        .arg(quality.format_selector())
+        .arg("-P")
+        .arg(directory)
+        .args(["-o", "%(title)s [%(id)s].%(ext)s"])
+        .arg("--progress-template")
+        .arg(format!(
+            "download:{PROGRESS_PREFIX} %(progress.downloaded_bytes)s \
+            %(progress.total_bytes)s %(progress.total_bytes_estimate)s %(info.title)s"
+        ))
+        // Final path is printed once merging and moving are done
+        .args(["--print", "after_move:filepath"])
+        .arg("--")
+        .arg(url);
+
+    let stdout = run_tool(command, timeout, cancel_token, |line: &str| {
+        if let Some((title, downloaded, total)) = parse_progress(line) {
+            on_progress(title, downloaded, total);
+        }
+    })
+    .await?;
+
+    let path = stdout
+        .lines()
+        .rev()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !line.starts_with(PROGRESS_PREFIX))
+        .ok_or("yt-dlp did not report the downloaded file")?;
+    Ok(PathBuf::from(path))
+}
+
+/// Parse lines of our `--progress-template`, sizes are `NA` when unknown
+fn parse_progress(line: &str) -> Option<(&str, u64, u64)> {
+    let mut parts = line.strip_prefix(PROGRESS_PREFIX)?.trim().splitn(4, ' ');
+    let downloaded = parse_bytes(parts.next()?)?;
+    let total = parse_bytes(parts.next()?);
+    let estimate = parse_bytes(parts.next()?);
+    let title = parts.next().unwrap_or("");
+    Some((title, downloaded, total.or(estimate).unwrap_or(0)))
+}
+
+fn parse_bytes(value: &str) -> Option<u64> {
+    value.parse::<f64>().ok().map(|bytes| bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn video_sites() {
+        let custom = vec!["media.example.com".to_string()];
+        let cases: [(&str, &[String], bool); 11] = [
+            ("https://www.youtube.com/watch?v=abc", &[], true),
+            ("https://youtu.be/abc", &[], true),
+            ("https://m.YouTube.com./watch?v=abc", &[], true),
+            ("https://vimeo.com/123", &[], true),
+            ("https://example.com/video.mp4", &[], false),
+            ("https://notyoutube.com/watch?v=abc", &[], false),
+            ("https://youtube.com.evil.com/watch?v=abc", &[], false),
+            ("http://127.0.0.1/watch?v=abc", &[], false),
+            ("https://media.example.com/v/1", &custom, true),
+            ("https://cdn.media.example.com/v/1", &custom, true),
+            ("https://www.youtube.com/watch?v=abc", &custom, false),
+        ];
+        for (url, domains, expected) in cases {
+            let url = Url::parse(url).unwrap();
+            assert_eq!(is_video_site(&url, domains), expected, "{url}");
+        }
+    }
+}